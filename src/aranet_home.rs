@@ -0,0 +1,227 @@
+//! CSV in the layout the Aranet Home app exports, so spreadsheets built around app exports work
+//! with ours too and old app exports can be imported.
+
+use std::io::{Read, Write};
+
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+
+use crate::{
+    types::{DeviceModel, Sample},
+    Error, Result,
+};
+
+/// Header of the time column as the app writes it, times are local to whoever exported
+pub const TIME_HEADER: &str = "Time(dd/mm/yyyy)";
+/// The app's own format, it quotes the header row but not the values
+const TIME_FORMAT: &str = "%d/%m/%Y %H:%M:%S";
+/// What app exports have used over the years and locales, tried in order
+const TIME_FORMATS: [&str; 4] = [
+    "%d/%m/%Y %H:%M:%S",
+    "%d/%m/%Y %H:%M",
+    "%d/%m/%Y %I:%M:%S %p",
+    "%d/%m/%Y %I:%M %p",
+];
+/// hPa per mmHg, the app exports pressure in whichever unit it displays
+const HPA_PER_MMHG: f64 = 1.333_224;
+
+/// Value columns in the order the app writes them, a file only has the ones its device measures
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Column {
+    Co2,
+    Radon,
+    Temp,
+    Humidity,
+    Preasure,
+    DoseRate,
+    Dose,
+}
+
+const COLUMNS: [Column; 7] = [
+    Column::Co2,
+    Column::Radon,
+    Column::Temp,
+    Column::Humidity,
+    Column::Preasure,
+    Column::DoseRate,
+    Column::Dose,
+];
+
+impl Column {
+    fn header(&self, fahrenheit: bool) -> &'static str {
+        match self {
+            Column::Co2 => "Carbon dioxide(ppm)",
+            Column::Radon => "Radon concentration(Bq/m³)",
+            Column::Temp if fahrenheit => "Temperature(°F)",
+            Column::Temp => "Temperature(°C)",
+            Column::Humidity => "Relative humidity(%)",
+            Column::Preasure => "Atmospheric pressure(hPa)",
+            Column::DoseRate => "Dose rate(µSv/h)",
+            Column::Dose => "Dose(mSv)",
+        }
+    }
+
+    fn value(&self, sample: &Sample, fahrenheit: bool) -> Option<String> {
+        match self {
+            Column::Co2 => sample.c02.map(|x| x.to_string()),
+            Column::Radon => sample.radon.map(|x| x.to_string()),
+            Column::Temp if fahrenheit => {
+                sample.temp.map(|x| format!("{:.1}", x * 9.0 / 5.0 + 32.0))
+            }
+            Column::Temp => sample.temp.map(|x| format!("{x:.1}")),
+            Column::Humidity => sample.humidity.map(|x| x.to_string()),
+            Column::Preasure => sample.preasure.map(|x| format!("{x:.1}")),
+            Column::DoseRate => sample.dose_rate.map(|x| format!("{x:.2}")),
+            Column::Dose => sample.dose.map(|x| format!("{x:.4}")),
+        }
+    }
+
+    /// Matches the app's headers regardless of unit, `Dose rate` has to be tried before `Dose`
+    fn from_header(header: &str) -> Option<Self> {
+        let header = header.trim();
+        [
+            ("Carbon dioxide", Column::Co2),
+            ("Radon", Column::Radon),
+            ("Temperature", Column::Temp),
+            ("Relative humidity", Column::Humidity),
+            ("Atmospheric pressure", Column::Preasure),
+            ("Dose rate", Column::DoseRate),
+            ("Dose", Column::Dose),
+        ]
+        .into_iter()
+        .find(|(prefix, _)| header.starts_with(prefix))
+        .map(|(_, column)| column)
+    }
+
+    /// Converts to the units `Sample` uses
+    fn set(&self, sample: &mut Sample, header: &str, value: f64) {
+        match self {
+            Column::Co2 => sample.c02 = Some(value.round() as u16),
+            Column::Radon => sample.radon = Some(value.round() as u32),
+            Column::Temp if header.contains("°F") => {
+                sample.temp = Some((value - 32.0) * 5.0 / 9.0)
+            }
+            Column::Temp => sample.temp = Some(value),
+            Column::Humidity => sample.humidity = Some(value),
+            Column::Preasure if header.contains("mmHg") => {
+                sample.preasure = Some(value * HPA_PER_MMHG)
+            }
+            Column::Preasure => sample.preasure = Some(value),
+            Column::DoseRate => sample.dose_rate = Some(value),
+            Column::Dose => sample.dose = Some(value),
+        }
+    }
+}
+
+/// The app exports a single device per file, which model it was shows in the columns
+fn model_of(columns: &[Column]) -> Option<DeviceModel> {
+    if columns.contains(&Column::Co2) {
+        Some(DeviceModel::Aranet4)
+    } else if columns.contains(&Column::Radon) {
+        Some(DeviceModel::AranetRadonPlus)
+    } else if columns.contains(&Column::DoseRate) {
+        Some(DeviceModel::AranetRadiation)
+    } else if columns.contains(&Column::Temp) {
+        Some(DeviceModel::Aranet2)
+    } else {
+        None
+    }
+}
+
+fn malformed(reason: String) -> Error {
+    Error::Malformed {
+        name: "Aranet Home CSV",
+        reason,
+    }
+}
+
+fn parse_time<Tz: TimeZone>(s: &str, tz: &Tz) -> Option<DateTime<Utc>> {
+    TIME_FORMATS
+        .iter()
+        .find_map(|f| NaiveDateTime::parse_from_str(s.trim(), f).ok())
+        .and_then(|x| tz.from_local_datetime(&x).earliest())
+        .map(|x| x.to_utc())
+}
+
+/// Reads an app export of `device` with times in `tz`, unknown columns are skipped and empty
+/// cells left as `None`
+pub fn read_csv<R: Read, Tz: TimeZone>(r: R, device: &str, tz: &Tz) -> Result<Vec<Sample>> {
+    let mut reader = csv::ReaderBuilder::new().flexible(true).from_reader(r);
+    let headers = reader.headers()?.clone();
+    if !headers.get(0).is_some_and(|x| x.trim().starts_with("Time")) {
+        return Err(malformed("first column isn't Time".to_string()));
+    }
+    let columns: Vec<(usize, &str, Column)> = headers
+        .iter()
+        .enumerate()
+        .skip(1)
+        .filter_map(|(i, x)| Column::from_header(x).map(|c| (i, x, c)))
+        .collect();
+    let model = model_of(&columns.iter().map(|x| x.2).collect::<Vec<_>>());
+
+    let mut samples = Vec::new();
+    for record in reader.records() {
+        let record = record?;
+        let line = record.position().map_or(0, |x| x.line());
+        let cell = record.get(0).unwrap_or_default();
+        let time = parse_time(cell, tz)
+            .ok_or_else(|| malformed(format!("line {line}: invalid time {cell:?}")))?;
+        let mut sample = Sample::empty(device, time);
+        sample.model = model;
+        for (i, header, column) in &columns {
+            let cell = record.get(*i).unwrap_or_default().trim();
+            if cell.is_empty() {
+                continue;
+            }
+            let value: f64 = cell
+                .parse()
+                .map_err(|_| malformed(format!("line {line}: invalid {header} {cell:?}")))?;
+            column.set(&mut sample, header, value);
+        }
+        samples.push(sample);
+    }
+    Ok(samples)
+}
+
+/// Writes `samples` of a single device with times in `tz`, columns are picked from the values
+/// the samples have
+pub fn write_csv<W: Write, Tz: TimeZone>(
+    mut w: W,
+    samples: &[Sample],
+    tz: &Tz,
+    fahrenheit: bool,
+) -> Result<()>
+where
+    Tz::Offset: std::fmt::Display,
+{
+    let columns: Vec<Column> = COLUMNS
+        .into_iter()
+        .filter(|x| samples.iter().any(|s| x.value(s, false).is_some()))
+        .collect();
+
+    let mut header = csv::WriterBuilder::new()
+        .quote_style(csv::QuoteStyle::Always)
+        .from_writer(&mut w);
+    header.write_record(
+        std::iter::once(TIME_HEADER).chain(columns.iter().map(|x| x.header(fahrenheit))),
+    )?;
+    header.flush()?;
+    drop(header);
+
+    let mut writer = csv::Writer::from_writer(w);
+    for sample in samples {
+        let time = sample
+            .time
+            .with_timezone(tz)
+            .format(TIME_FORMAT)
+            .to_string();
+        writer.write_record(
+            std::iter::once(time).chain(
+                columns
+                    .iter()
+                    .map(|x| x.value(sample, fahrenheit).unwrap_or_default()),
+            ),
+        )?;
+    }
+    writer.flush()?;
+    Ok(())
+}
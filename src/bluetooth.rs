@@ -1,183 +1,913 @@
-use std::{
-    future::Future,
-    io::{Read, Write},
-    pin::Pin,
-    process::{Command, Stdio},
-};
+use std::io::{IsTerminal, Write};
 
-use anyhow::{anyhow, Result};
 use arrayref::{array_ref, array_refs};
-use bluer::{
-    agent::{self, ReqResult},
-    gatt::remote::Characteristic,
-    Device,
-};
-use uuid::Uuid;
+use bluer::agent;
+use chrono::{DateTime, TimeDelta, Utc};
 
-use crate::types::{CurrentReading, Temp};
+use crate::{
+    pinentry,
+    transport::{Characteristic, Peripheral},
+    types::{
+        Aranet2Reading, BluetoothRange, Calibration, CalibrationState, CurrentReading, DeviceInfo,
+        DeviceModel, HistoryRecord, Interval, LogState, Measured, RadiationReading, RadonReading,
+        Reading, Settings, SyncState, Temp, TempUnit,
+    },
+    Error, Result,
+};
 
 pub fn str_mac_to_array(mac_address: &str) -> Result<[u8; 6]> {
+    let invalid = || Error::Config(format!("Invalid MAC address {mac_address:?}"));
     let mut mac_array = [0u8; 6];
+    let mut parts = mac_address.split(':');
 
-    for (i, part) in mac_address.split(':').enumerate() {
-        mac_array[i] = u8::from_str_radix(part, 16)?;
+    for byte in mac_array.iter_mut() {
+        let part = parts.next().ok_or_else(invalid)?;
+        *byte = u8::from_str_radix(part, 16).map_err(|_| invalid())?;
+    }
+    if parts.next().is_some() {
+        return Err(invalid());
     }
 
     Ok(mac_array)
 }
 
-pub fn get_passkey(
-    req: agent::RequestPasskey,
-) -> Pin<Box<dyn Future<Output = ReqResult<u32>> + Send>> {
-    println!("Passkey requested");
-    Box::pin(async move {
-        println!(
-            "Device requesting PIN code for {} on {}",
-            req.device, req.adapter
-        );
-
-        let mut child = Command::new("pinentry-qt")
-            .stdin(Stdio::piped())
-            .stdout(Stdio::piped())
-            .spawn()
-            .unwrap();
-
-        let mut stdin = child.stdin.take().unwrap();
-        let mut stdout = child.stdout.take().unwrap();
-
-        // Write commands to pinentry
-        stdin.write_all(b"SETTITLE Bluetooth PIN\n").unwrap();
-        stdin
-            .write_all(format!("SETDESC Enter PIN for device {}\n", req.device).as_bytes())
-            .unwrap();
-        stdin.write_all(b"GETPIN\n").unwrap();
-
-        // Read response line by line
-        let mut pin = String::new();
-        let mut buf = [0u8; 1024];
-
-        loop {
-            let n = stdout.read(&mut buf).unwrap();
-            if n == 0 {
-                break;
-            }
+/// Passkeys are the 6 digits the device shows on it's screen
+pub fn parse_passkey(input: &str) -> Result<u32> {
+    let input = input.trim();
+    match input.parse() {
+        Ok(passkey) if input.len() == 6 && input.bytes().all(|x| x.is_ascii_digit()) => Ok(passkey),
+        _ => Err(Error::Malformed {
+            name: "passkey",
+            reason: "must be 6 digits".to_string(),
+        }),
+    }
+}
 
-            let response = String::from_utf8_lossy(&buf[..n]);
-            if response.starts_with("D ") {
-                pin = response[2..8].trim().to_string();
-                break;
+/// Pin entry for the pairing agent, tries `pinentry`, then a terminal prompt, then `configured`
+/// (from config or the environment) and rejects the request when none of them gives a passkey.
+/// Cancelling the pinentry dialog rejects right away.
+pub fn get_passkey(pinentry: String, configured: Option<u32>) -> agent::RequestPasskeyFn {
+    Box::new(move |req| {
+        let pinentry = pinentry.clone();
+        Box::pin(async move {
+            println!(
+                "Device requesting PIN code for {} on {}",
+                req.device, req.adapter
+            );
+
+            let device = req.device;
+            let entered = tokio::task::spawn_blocking(move || {
+                pinentry_passkey(&pinentry, device)
+                    .inspect_err(|e| eprintln!("{pinentry} unavailable: {e:?}"))
+                    .or_else(|_| tty_passkey(device).map(Some))
+                    .inspect_err(|e| eprintln!("Terminal pin entry unavailable: {e:?}"))
+                    .map_err(|_| ())
+            })
+            .await;
+
+            let entered = match entered {
+                Ok(Ok(None)) => {
+                    eprintln!("Pin entry cancelled for {device}");
+                    return Err(agent::ReqError::Canceled);
+                }
+                Ok(Ok(passkey)) => passkey,
+                _ => None,
+            };
+            match entered.or(configured) {
+                Some(passkey) => Ok(passkey),
+                None => {
+                    eprintln!(
+                        "No passkey for {device}, set `passkey` in the config or ARANET_PASSKEY"
+                    );
+                    Err(agent::ReqError::Rejected)
+                }
             }
-        }
+        })
+    })
+}
+
+fn pinentry_passkey(program: &str, device: bluer::Address) -> Result<Option<u32>> {
+    let pin = pinentry::get_pin(
+        program,
+        "Bluetooth PIN",
+        &format!("Enter PIN for device {device}"),
+    )?;
+    pin.map(|x| parse_passkey(&x)).transpose()
+}
 
-        child.kill().unwrap();
+fn tty_passkey(device: bluer::Address) -> Result<u32> {
+    let stdin = std::io::stdin();
+    if !stdin.is_terminal() {
+        return Err(std::io::Error::other("stdin isn't a terminal").into());
+    }
+    eprint!("Enter PIN for device {device}: ");
+    std::io::stderr().flush()?;
+    let mut input = String::new();
+    stdin.read_line(&mut input)?;
+    parse_passkey(&input)
+}
 
-        dbg!(&pin);
+#[derive(Debug)]
+pub struct EndPoints<C = bluer::gatt::remote::Characteristic> {
+    model_number: Option<C>,
+    serial_no: Option<C>,
+    firmware_rev: Option<C>,
+    hardware_rev: Option<C>,
+    software_rev: Option<C>,
+    manufacturer_name: Option<C>,
+    battery_level: Option<C>,
+    sensor_state: Option<C>,
+    cmd: Option<C>,
+    calibration_data: Option<C>,
+    current_readings: Option<C>,
+    current_readings_ar2: Option<C>,
+    total_readings: Option<C>,
+    interval: Option<C>,
+    history_readings_v1: Option<C>,
+    seconds_since_update: Option<C>,
+    history_readings_v2: Option<C>,
+    current_readings_det: Option<C>,
+    current_readings_a: Option<C>,
+    current_readings_a_ar2: Option<C>,
+}
 
-        if pin.is_empty() {
-            Err(bluer::agent::ReqError::Rejected)
-        } else {
-            Ok(pin.parse::<u32>().unwrap())
+impl<C> Default for EndPoints<C> {
+    fn default() -> Self {
+        Self {
+            model_number: None,
+            serial_no: None,
+            firmware_rev: None,
+            hardware_rev: None,
+            software_rev: None,
+            manufacturer_name: None,
+            battery_level: None,
+            sensor_state: None,
+            cmd: None,
+            calibration_data: None,
+            current_readings: None,
+            current_readings_ar2: None,
+            total_readings: None,
+            interval: None,
+            history_readings_v1: None,
+            seconds_since_update: None,
+            history_readings_v2: None,
+            current_readings_det: None,
+            current_readings_a: None,
+            current_readings_a_ar2: None,
         }
-    })
+    }
 }
 
-#[derive(Debug, Default)]
-pub struct EndPoints {
-    battery_level: Option<Characteristic>,
-    sensor_state: Option<Characteristic>,
-    cmd: Option<Characteristic>,
-    calibration_data: Option<Characteristic>,
-    current_readings: Option<Characteristic>,
-    current_readings_ar2: Option<Characteristic>,
-    total_readings: Option<Characteristic>,
-    interval: Option<Characteristic>,
-    history_readings_v1: Option<Characteristic>,
-    seconds_since_update: Option<Characteristic>,
-    history_readings_v2: Option<Characteristic>,
-    current_readings_det: Option<Characteristic>,
-    current_readings_a: Option<Characteristic>,
-    current_readings_a_ar2: Option<Characteristic>,
-}
-
-impl EndPoints {
-    pub async fn read(&self) -> Result<CurrentReading> {
+/// Errors out unless `bytes` holds at least `len` bytes
+fn check_len(bytes: &[u8], len: usize, name: &'static str) -> Result<()> {
+    if bytes.len() < len {
+        return Err(Error::ShortPayload {
+            name,
+            len: bytes.len(),
+            expected: len,
+        });
+    }
+    Ok(())
+}
+
+impl<C: Characteristic> EndPoints<C> {
+    /// Worked out from the model specific characteristics the device exposes, newer models
+    /// which share current_readings_a_ar2 are told apart by the device type it leads with
+    pub async fn model(&self) -> Result<DeviceModel> {
+        if self.current_readings.is_some() {
+            return Ok(DeviceModel::Aranet4);
+        }
+        if let Some(c) = &self.current_readings_a_ar2 {
+            let bytes = c.read().await?;
+            check_len(&bytes, 2, "current_readings_a_ar2")?;
+            return model_from_type(
+                u16::from_le_bytes([bytes[0], bytes[1]]),
+                "current_readings_a_ar2",
+            );
+        }
+        if self.current_readings_ar2.is_some() {
+            return Ok(DeviceModel::Aranet2);
+        }
+        Err(Error::Unsupported(
+            "Unknown device model, no current readings characteristic".to_string(),
+        ))
+    }
+
+    /// Prefers the characteristics which also carry the sample age, so the reading gets a real
+    /// measurement time
+    pub async fn read(&self) -> Result<Reading> {
+        for (c, name) in [
+            (&self.current_readings_det, "current_readings_det"),
+            (&self.current_readings_a, "current_readings_a"),
+        ] {
+            if let Some(c) = c {
+                let bytes = c.read().await?;
+                check_len(&bytes, 13, name)?;
+                let src = array_ref![bytes, 0, 13];
+                let (readings, interval, ago) = array_refs![src, 9, 2, 2];
+                let mut readings = decode_current_readings(readings);
+                readings.measured = Some(Measured::new(
+                    u16::from_le_bytes(*ago),
+                    u16::from_le_bytes(*interval),
+                ));
+                return Ok(Reading::Aranet4(readings));
+            }
+        }
         if let Some(c) = &self.current_readings {
             let bytes = c.read().await?;
-            let src = array_ref![bytes, 0, 9];
-            let (c02, temp, preasure, humidity, bat, status) = array_refs![src, 2, 2, 2, 1, 1, 1];
-            return Ok(CurrentReading {
-                c02: u16::from_le_bytes(*c02),
+            check_len(&bytes, 9, "current_readings")?;
+            return Ok(Reading::Aranet4(decode_current_readings(array_ref![
+                bytes, 0, 9
+            ])));
+        }
+        if let Some(c) = &self.current_readings_a_ar2 {
+            return decode_readings_a(&c.read().await?);
+        }
+        if let Some(c) = &self.current_readings_ar2 {
+            let bytes = c.read().await?;
+            check_len(&bytes, 6, "current_readings_ar2")?;
+            return Ok(Reading::Aranet2(decode_aranet2_readings(array_ref![
+                bytes, 0, 6
+            ])));
+        }
+        Err(Error::MissingCharacteristic("current_readings"))
+    }
+}
+
+/// Device type leading current_readings_a_ar2 and the advertisements of models other than the
+/// Aranet4
+fn model_from_type(code: u16, name: &'static str) -> Result<DeviceModel> {
+    match code {
+        1 => Ok(DeviceModel::Aranet2),
+        2 => Ok(DeviceModel::AranetRadiation),
+        3 => Ok(DeviceModel::AranetRadonPlus),
+        _ => Err(Error::Malformed {
+            name,
+            reason: format!("unknown device type {code}"),
+        }),
+    }
+}
+
+/// current_readings_a_ar2 holds device type (2), interval (2) and seconds since update (2)
+/// followed by the model specific readings
+fn decode_readings_a(bytes: &[u8]) -> Result<Reading> {
+    const NAME: &str = "current_readings_a_ar2";
+    check_len(bytes, 6, NAME)?;
+    let header = array_ref![bytes, 0, 6];
+    let (code, interval, ago) = array_refs![header, 2, 2, 2];
+    let measured = Some(Measured::new(
+        u16::from_le_bytes(*ago),
+        u16::from_le_bytes(*interval),
+    ));
+    let data = &bytes[6..];
+    match model_from_type(u16::from_le_bytes(*code), NAME)? {
+        DeviceModel::Aranet2 => {
+            check_len(data, 6, NAME)?;
+            let mut readings = decode_aranet2_readings(array_ref![data, 0, 6]);
+            readings.measured = measured;
+            Ok(Reading::Aranet2(readings))
+        }
+        DeviceModel::AranetRadiation => {
+            check_len(data, 18, NAME)?;
+            let src = array_ref![data, 0, 18];
+            let (bat, dose_rate, dose, dose_duration, status) = array_refs![src, 1, 4, 4, 8, 1];
+            Ok(Reading::Radiation(RadiationReading {
+                dose_rate: u32::from_le_bytes(*dose_rate),
+                dose: u32::from_le_bytes(*dose),
+                dose_duration: u64::from_le_bytes(*dose_duration),
+                bat: bat[0],
+                status: status[0],
+                measured,
+            }))
+        }
+        DeviceModel::AranetRadonPlus => {
+            check_len(data, 12, NAME)?;
+            let src = array_ref![data, 0, 12];
+            let (bat, temp, preasure, humidity, radon, status) = array_refs![src, 1, 2, 2, 2, 4, 1];
+            Ok(Reading::Radon(RadonReading {
+                radon: u32::from_le_bytes(*radon),
                 temp: Temp::new(u16::from_le_bytes(*temp)),
                 preasure: u16::from_le_bytes(*preasure),
-                humidity: humidity[0],
+                humidity: u16::from_le_bytes(*humidity),
                 bat: bat[0],
                 status: status[0],
-            });
+                measured,
+            }))
+        }
+        DeviceModel::Aranet4 => Err(Error::Unsupported(format!("Aranet4 doesn't use {NAME}"))),
+    }
+}
+
+/// Layout: bat (1), temp (2), humidity (2), status (1)
+///
+/// current_readings_ar2 holds just this, current_readings_a_ar2 prefixes it with
+/// device type (2), interval (2) and seconds since update (2)
+fn decode_aranet2_readings(src: &[u8; 6]) -> Aranet2Reading {
+    let (bat, temp, humidity, status) = array_refs![src, 1, 2, 2, 1];
+    Aranet2Reading {
+        temp: Temp::new(u16::from_le_bytes(*temp)),
+        humidity: u16::from_le_bytes(*humidity),
+        bat: bat[0],
+        status: status[0],
+        measured: None,
+    }
+}
+
+/// Shared by the current_readings characteristic and advertisements
+fn decode_current_readings(src: &[u8; 9]) -> CurrentReading {
+    let (c02, temp, preasure, humidity, bat, status) = array_refs![src, 2, 2, 2, 1, 1, 1];
+    CurrentReading {
+        c02: u16::from_le_bytes(*c02),
+        temp: Temp::new(u16::from_le_bytes(*temp)),
+        preasure: u16::from_le_bytes(*preasure),
+        humidity: humidity[0],
+        bat: bat[0],
+        status: status[0],
+        measured: None,
+    }
+}
+
+/// Bluetooth SIG company identifier of SAF Tehnika, key of the advertised manufacturer data
+pub const MANUFACTURER_ID: u16 = 0x0702;
+
+const ADV_HEADER_LEN: usize = 8;
+const ADV_READINGS_LEN: usize = 14;
+const ADV_FLAG_INTEGRATIONS: u8 = 1 << 5;
+
+/// Manufacturer specific data of an Aranet4 advertisement
+///
+/// Layout: flags (1), patch (2), minor (1), major (1), reserved (3), then only with Smart Home
+/// integration on: co2 (2), temp (2), preasure (2), humidity (1), bat (1), status (1),
+/// interval (2), seconds since update (2), counter (1)
+///
+/// Other models lead with their device type (1), those are recognized but not decoded.
+#[derive(Debug)]
+pub struct Advertisement {
+    pub flags: u8,
+    /// (major, minor, patch)
+    pub version: (u8, u8, u16),
+    pub readings: Option<CurrentReading>,
+    pub interval: Option<u16>,
+    pub seconds_since_update: Option<u16>,
+    /// Bumped with every new measurement
+    pub counter: Option<u8>,
+}
+
+impl Advertisement {
+    pub fn parse(bytes: &[u8]) -> Result<Self> {
+        check_len(bytes, 1, "advertisement")?;
+        // Other models lead with their device type, which never has the integrations flag set.
+        // Without it only the length tells an Aranet4 apart, as it's flags can look like a type.
+        let aranet4 = bytes[0] & ADV_FLAG_INTEGRATIONS != 0
+            || [ADV_HEADER_LEN, ADV_HEADER_LEN + ADV_READINGS_LEN].contains(&bytes.len());
+        if !aranet4 {
+            match model_from_type(bytes[0] as u16, "advertisement") {
+                Ok(model) => {
+                    return Err(Error::Unsupported(format!(
+                        "Passive mode can't decode {model} advertisements, drop --passive"
+                    )))
+                }
+                // A cut short Aranet4 header rather than an unknown device
+                Err(_) if bytes.len() < ADV_HEADER_LEN => {}
+                Err(e) => return Err(e),
+            }
+        }
+        check_len(bytes, ADV_HEADER_LEN, "advertisement")?;
+        let header = array_ref![bytes, 0, ADV_HEADER_LEN];
+        let (flags, patch, minor, major, _) = array_refs![header, 1, 2, 1, 1, 3];
+
+        let mut adv = Self {
+            flags: flags[0],
+            version: (major[0], minor[0], u16::from_le_bytes(*patch)),
+            readings: None,
+            interval: None,
+            seconds_since_update: None,
+            counter: None,
+        };
+
+        if adv.flags & ADV_FLAG_INTEGRATIONS == 0 {
+            return Ok(adv);
+        }
+
+        check_len(bytes, ADV_HEADER_LEN + ADV_READINGS_LEN, "advertisement")?;
+        let src = array_ref![bytes, ADV_HEADER_LEN, ADV_READINGS_LEN];
+        let (readings, interval, ago, counter) = array_refs![src, 9, 2, 2, 1];
+        let mut readings = decode_current_readings(readings);
+        let (interval, ago) = (u16::from_le_bytes(*interval), u16::from_le_bytes(*ago));
+        readings.measured = Some(Measured::new(ago, interval));
+        adv.readings = Some(readings);
+        adv.interval = Some(interval);
+        adv.seconds_since_update = Some(ago);
+        adv.counter = Some(counter[0]);
+
+        Ok(adv)
+    }
+
+    pub fn integrations(&self) -> bool {
+        self.flags & ADV_FLAG_INTEGRATIONS != 0
+    }
+}
+
+const CMD_HISTORY_V2: u8 = 0x61;
+const CMD_SET_INTERVAL: u8 = 0x90;
+const CMD_SET_SMART_HOME: u8 = 0x91;
+const CMD_SET_RANGE: u8 = 0x92;
+const CMD_SET_TEMP_UNIT: u8 = 0x93;
+const CMD_CALIBRATE: u8 = 0x94;
+const CMD_SET_AUTO_CALIBRATION: u8 = 0x95;
+
+const HISTORY_V2_HEADER_LEN: usize = 10;
+/// Packets for another parameter tolerated before giving up on a history download
+const MAX_STALE_HISTORY_PACKETS: usize = 16;
+
+/// Flags held in the second byte of sensor_state
+const STATE_FLAG_AUTO_CALIBRATION: u8 = 1 << 2;
+const STATE_FLAG_FAHRENHEIT: u8 = 1 << 4;
+const STATE_FLAG_SMART_HOME: u8 = 1 << 5;
+const STATE_FLAG_RANGE_EXTENDED: u8 = 1 << 7;
+
+/// Everything written to the cmd characteristic
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    /// Selects which parameter history_readings_v2 serves, starting at the 1 based index
+    HistoryV2 {
+        param: HistoryParam,
+        start: u16,
+    },
+    SetInterval(Interval),
+    SetSmartHome(bool),
+    SetRange(BluetoothRange),
+    SetTempUnit(TempUnit),
+    /// Forces a 400ppm calibration, the device needs to be outdoors in fresh air
+    Calibrate,
+    SetAutoCalibration(bool),
+}
+
+impl Command {
+    pub fn bytes(&self) -> Vec<u8> {
+        match *self {
+            Command::HistoryV2 { param, start } => {
+                let [lo, hi] = start.to_le_bytes();
+                vec![CMD_HISTORY_V2, param as u8, lo, hi]
+            }
+            Command::SetInterval(interval) => vec![CMD_SET_INTERVAL, interval.minutes()],
+            Command::SetSmartHome(on) => vec![CMD_SET_SMART_HOME, on as u8],
+            Command::SetRange(range) => {
+                vec![CMD_SET_RANGE, (range == BluetoothRange::Extended) as u8]
+            }
+            Command::SetTempUnit(unit) => {
+                vec![CMD_SET_TEMP_UNIT, (unit == TempUnit::Fahrenheit) as u8]
+            }
+            Command::Calibrate => vec![CMD_CALIBRATE, 0x00],
+            Command::SetAutoCalibration(on) => vec![CMD_SET_AUTO_CALIBRATION, on as u8],
+        }
+    }
+}
+
+/// Parameters which can be requested from the history_readings_v2 characteristic
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum HistoryParam {
+    Temp = 1,
+    Humidity = 2,
+    Preasure = 3,
+    C02 = 4,
+}
+
+impl HistoryParam {
+    /// Byte width of a single sample in a history packet
+    pub fn sample_size(&self) -> usize {
+        match self {
+            HistoryParam::Humidity => 1,
+            _ => 2,
+        }
+    }
+}
+
+/// One notification worth of history_readings_v2 data
+#[derive(Debug)]
+pub struct HistoryPacket {
+    pub param: u8,
+    pub interval: u16,
+    pub total_readings: u16,
+    pub seconds_since_update: u16,
+    /// 1 based index of the first sample in this packet
+    pub start: u16,
+    pub samples: Vec<u16>,
+}
+
+impl HistoryPacket {
+    pub fn parse(bytes: &[u8], param: HistoryParam) -> Result<Self> {
+        check_len(bytes, HISTORY_V2_HEADER_LEN, "history_readings_v2")?;
+        let src = array_ref![bytes, 0, HISTORY_V2_HEADER_LEN];
+        let (p, interval, total, ago, start, count) = array_refs![src, 1, 2, 2, 2, 2, 1];
+
+        let size = param.sample_size();
+        let count = count[0] as usize;
+        let data = &bytes[HISTORY_V2_HEADER_LEN..];
+        check_len(
+            bytes,
+            HISTORY_V2_HEADER_LEN + count * size,
+            "history_readings_v2",
+        )?;
+
+        let samples = data
+            .chunks_exact(size)
+            .take(count)
+            .map(|x| match x {
+                [a] => *a as u16,
+                [a, b] => u16::from_le_bytes([*a, *b]),
+                _ => unreachable!(),
+            })
+            .collect();
+
+        Ok(Self {
+            param: p[0],
+            interval: u16::from_le_bytes(*interval),
+            total_readings: u16::from_le_bytes(*total),
+            seconds_since_update: u16::from_le_bytes(*ago),
+            start: u16::from_le_bytes(*start),
+            samples,
+        })
+    }
+}
+
+/// Reads a string characteristic, None if the device doesn't expose it
+async fn read_string<C: Characteristic>(c: &Option<C>) -> Result<Option<String>> {
+    let Some(c) = c else {
+        return Ok(None);
+    };
+    let bytes = c.read().await?;
+    let value = String::from_utf8_lossy(&bytes);
+    Ok(Some(value.trim_end_matches('\0').trim().to_string()))
+}
+
+async fn read_u16<C: Characteristic>(c: &Option<C>, name: &'static str) -> Result<u16> {
+    let c = c.as_ref().ok_or(Error::MissingCharacteristic(name))?;
+    let bytes = c.read().await?;
+    check_len(&bytes, 2, name)?;
+    Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+impl<C: Characteristic> EndPoints<C> {
+    /// Number of samples currently held in the on-device log
+    pub async fn read_total_readings(&self) -> Result<u16> {
+        read_u16(&self.total_readings, "total_readings").await
+    }
+
+    /// Measurement interval in seconds
+    pub async fn read_interval(&self) -> Result<u16> {
+        read_u16(&self.interval, "interval").await
+    }
+
+    pub async fn read_seconds_since_update(&self) -> Result<u16> {
+        read_u16(&self.seconds_since_update, "seconds_since_update").await
+    }
+
+    /// When the current value was measured, for readings whose characteristic doesn't say
+    pub async fn read_measured(&self) -> Result<Measured> {
+        let interval = self.read_interval().await?;
+        let ago = self.read_seconds_since_update().await?;
+        Ok(Measured::new(ago, interval))
+    }
+
+    /// Battery level from the standard battery service, available on firmware v1.2.0 and later
+    pub async fn read_battery_level(&self) -> Result<u8> {
+        let c = self
+            .battery_level
+            .as_ref()
+            .ok_or(Error::MissingCharacteristic("battery_level"))?;
+        let bytes = c.read().await?;
+        check_len(&bytes, 1, "battery_level")?;
+        Ok(bytes[0])
+    }
+
+    /// Reads the device information service and battery level, missing characteristics are
+    /// left empty
+    pub async fn read_info(&self) -> Result<DeviceInfo> {
+        Ok(DeviceInfo {
+            model_number: read_string(&self.model_number).await?,
+            serial_number: read_string(&self.serial_no).await?,
+            firmware_revision: read_string(&self.firmware_rev).await?,
+            hardware_revision: read_string(&self.hardware_rev).await?,
+            software_revision: read_string(&self.software_rev).await?,
+            manufacturer_name: read_string(&self.manufacturer_name).await?,
+            battery_level: match self.battery_level {
+                Some(_) => Some(self.read_battery_level().await?),
+                None => None,
+            },
+        })
+    }
+
+    pub async fn send(&self, cmd: Command) -> Result<()> {
+        let c = self
+            .cmd
+            .as_ref()
+            .ok_or(Error::MissingCharacteristic("cmd"))?;
+        c.write(&cmd.bytes()).await
+    }
+
+    /// Raw sensor_state flags, see the STATE_FLAG_* constants
+    async fn read_state_flags(&self) -> Result<u8> {
+        let c = self
+            .sensor_state
+            .as_ref()
+            .ok_or(Error::MissingCharacteristic("sensor_state"))?;
+        let bytes = c.read().await?;
+        check_len(&bytes, 2, "sensor_state")?;
+        Ok(bytes[1])
+    }
+
+    pub async fn read_settings(&self) -> Result<Settings> {
+        let seconds = self.read_interval().await?;
+        let interval = Interval::from_seconds(seconds).ok_or(Error::Malformed {
+            name: "interval",
+            reason: format!("unexpected interval {seconds}s"),
+        })?;
+        let flags = self.read_state_flags().await?;
+
+        Ok(Settings {
+            interval,
+            smart_home: flags & STATE_FLAG_SMART_HOME != 0,
+            range: if flags & STATE_FLAG_RANGE_EXTENDED != 0 {
+                BluetoothRange::Extended
+            } else {
+                BluetoothRange::Standard
+            },
+            temp_unit: if flags & STATE_FLAG_FAHRENHEIT != 0 {
+                TempUnit::Fahrenheit
+            } else {
+                TempUnit::Celsius
+            },
+        })
+    }
+
+    /// Sends a settings command, then reads the settings back to make sure it took effect
+    pub async fn apply(&self, cmd: Command) -> Result<Settings> {
+        self.send(cmd).await?;
+        let settings = self.read_settings().await?;
+
+        let applied = match cmd {
+            Command::SetInterval(interval) => settings.interval == interval,
+            Command::SetSmartHome(on) => settings.smart_home == on,
+            Command::SetRange(range) => settings.range == range,
+            Command::SetTempUnit(unit) => settings.temp_unit == unit,
+            Command::HistoryV2 { .. } | Command::Calibrate | Command::SetAutoCalibration(_) => {
+                return Err(Error::Unsupported(format!(
+                    "{cmd:?} isn't a settings command"
+                )))
+            }
+        };
+        if !applied {
+            return Err(Error::NotApplied(format!("{cmd:?}")));
+        }
+
+        Ok(settings)
+    }
+
+    /// Layout of calibration_data: state (1), progress (1)
+    pub async fn read_calibration(&self) -> Result<Calibration> {
+        let c = self
+            .calibration_data
+            .as_ref()
+            .ok_or(Error::MissingCharacteristic("calibration_data"))?;
+        let bytes = c.read().await?;
+        check_len(&bytes, 2, "calibration_data")?;
+        let flags = self.read_state_flags().await?;
+
+        Ok(Calibration {
+            state: CalibrationState::from_code(bytes[0]),
+            progress: bytes[1],
+            auto: flags & STATE_FLAG_AUTO_CALIBRATION != 0,
+        })
+    }
+
+    /// Starts a forced 400ppm calibration, returns once the device reports it as running
+    pub async fn calibrate(&self) -> Result<Calibration> {
+        self.send(Command::Calibrate).await?;
+        let calibration = self.read_calibration().await?;
+        match calibration.state {
+            CalibrationState::InProgress | CalibrationState::EndRequest => Ok(calibration),
+            state => Err(Error::NotApplied(format!(
+                "{:?}, calibration state: {state}",
+                Command::Calibrate
+            ))),
+        }
+    }
+
+    pub async fn set_auto_calibration(&self, on: bool) -> Result<Calibration> {
+        self.send(Command::SetAutoCalibration(on)).await?;
+        let calibration = self.read_calibration().await?;
+        if calibration.auto != on {
+            return Err(Error::NotApplied(format!(
+                "{:?}",
+                Command::SetAutoCalibration(on)
+            )));
+        }
+        Ok(calibration)
+    }
+
+    /// Reads `count` samples of a single parameter, starting at the 1 based index `start`
+    pub async fn read_history_param(
+        &self,
+        param: HistoryParam,
+        start: u16,
+        count: u16,
+    ) -> Result<Vec<u16>> {
+        let history = self
+            .history_readings_v2
+            .as_ref()
+            .ok_or(Error::MissingCharacteristic("history_readings_v2"))?;
+
+        self.send(Command::HistoryV2 { param, start }).await?;
+
+        let mut samples: Vec<u16> = Vec::with_capacity(count as usize);
+        let mut stale = 0;
+        while samples.len() < count as usize {
+            let packet = HistoryPacket::parse(&history.read().await?, param)?;
+            // Packets for a previously requested parameter may still be queued
+            if packet.param != param as u8 {
+                stale += 1;
+                if stale > MAX_STALE_HISTORY_PACKETS {
+                    return Err(Error::Timeout("waiting for history_readings_v2"));
+                }
+                continue;
+            }
+            if packet.samples.is_empty() {
+                break;
+            }
+            let end = packet.start as usize + packet.samples.len();
+            samples.extend(packet.samples);
+            if end > packet.total_readings as usize {
+                break;
+            }
+        }
+        samples.truncate(count as usize);
+
+        Ok(samples)
+    }
+
+    pub async fn read_log_state(&self) -> Result<LogState> {
+        let total = self.read_total_readings().await?;
+        let interval = self.read_interval().await?;
+        let ago = self.read_seconds_since_update().await?;
+        Ok(LogState {
+            total,
+            interval,
+            newest: Utc::now() - TimeDelta::seconds(ago as i64),
+        })
+    }
+
+    /// Records measured after `last`, empty unless at least one measurement was missed. Used
+    /// to fill the gap left by an outage.
+    pub async fn read_missed(&self, last: DateTime<Utc>) -> Result<Vec<HistoryRecord>> {
+        let log = self.read_log_state().await?;
+        let interval = TimeDelta::seconds(log.interval as i64);
+        if log.interval == 0 || log.newest - last < interval * 3 / 2 {
+            return Ok(Vec::new());
         }
-        Err(anyhow!("Failed"))
+        let last = SyncState {
+            interval: log.interval,
+            newest: last,
+        };
+        Ok(self.read_history_since(Some(&last)).await?.0)
+    }
+
+    /// Downloads the complete on-device log, oldest sample first
+    pub async fn read_history(&self) -> Result<Vec<HistoryRecord>> {
+        Ok(self.read_history_since(None).await?.0)
     }
+
+    /// Downloads only the records measured after `last`, everything when it's `None`, along
+    /// with the state to pass in next time
+    pub async fn read_history_since(
+        &self,
+        last: Option<&SyncState>,
+    ) -> Result<(Vec<HistoryRecord>, SyncState)> {
+        let model = self.model().await?;
+        if model != DeviceModel::Aranet4 {
+            return Err(Error::Unsupported(format!(
+                "History download isn't supported on {model}"
+            )));
+        }
+
+        let log = self.read_log_state().await?;
+        let unchanged = last.copied().unwrap_or(SyncState {
+            interval: log.interval,
+            newest: log.newest,
+        });
+        let start = log.first_new(last);
+        if start > log.total {
+            return Ok((Vec::new(), unchanged));
+        }
+        let count = log.total - start + 1;
+
+        let temp = self
+            .read_history_param(HistoryParam::Temp, start, count)
+            .await?;
+        let humidity = self
+            .read_history_param(HistoryParam::Humidity, start, count)
+            .await?;
+        let preasure = self
+            .read_history_param(HistoryParam::Preasure, start, count)
+            .await?;
+        let c02 = self
+            .read_history_param(HistoryParam::C02, start, count)
+            .await?;
+
+        let len = [temp.len(), humidity.len(), preasure.len(), c02.len()]
+            .into_iter()
+            .min()
+            .unwrap_or(0);
+
+        let records: Vec<HistoryRecord> = (0..len)
+            .map(|i| HistoryRecord {
+                time: log.time_of(start + i as u16),
+                c02: c02[i],
+                temp: Temp::new(temp[i]),
+                preasure: preasure[i],
+                humidity: humidity[i] as u8,
+            })
+            .collect();
+        let state = match records.last() {
+            Some(x) => SyncState {
+                interval: log.interval,
+                newest: x.time,
+            },
+            None => unchanged,
+        };
+        Ok((records, state))
+    }
+}
+
+#[rustfmt::skip]
+pub mod uuids {
+    use uuid::Uuid;
+
+    pub const SERVICE_GAP: Uuid = Uuid::from_u128(0x0000180000001000800000805f9b34fb);
+    pub const CHAR_DEVICE_NAME: Uuid = Uuid::from_u128(0x00002a0000001000800000805f9b34fb);
+    pub const CHAR_APPEARANCE: Uuid = Uuid::from_u128(0x00002a0100001000800000805f9b34fb);
+
+    pub const SERVICE_DIS: Uuid = Uuid::from_u128(0x0000180a00001000800000805f9b34fb);
+    pub const CHAR_SYSTEM_ID: Uuid = Uuid::from_u128(0x00002a2300001000800000805f9b34fb);
+    pub const CHAR_MODEL_NUMBER: Uuid = Uuid::from_u128(0x00002a2400001000800000805f9b34fb);
+    pub const CHAR_SERIAL_NO: Uuid = Uuid::from_u128(0x00002a2500001000800000805f9b34fb);
+    pub const CHAR_SW_REV: Uuid = Uuid::from_u128(0x00002a2600001000800000805f9b34fb); // Firmware revision
+    pub const CHAR_HW_REV: Uuid = Uuid::from_u128(0x00002a2700001000800000805f9b34fb);
+    pub const CHAR_SW_REV_FACTORY: Uuid = Uuid::from_u128(0x00002a2800001000800000805f9b34fb); // Software revision
+    pub const CHAR_MANUFACTURER_NAME: Uuid = Uuid::from_u128(0x00002a2900001000800000805f9b34fb);
+
+    pub const SERVICE_BATTTERY: Uuid = Uuid::from_u128(0x0000180f00001000800000805f9b34fb); // v1.2.0 and later
+    pub const CHAR_BATTERY_LEVEL: Uuid = Uuid::from_u128(0x00002a1900001000800000805f9b34fb);
+
+    pub const SERVICE_SAF_TEHNIKA: Uuid = Uuid::from_u128(0x0000fce000001000800000805f9b34fb); // v1.2.0 and later
+    pub const CHAR_SENSOR_STATE: Uuid = Uuid::from_u128(0xf0cd140195da4f4b9ac8aa55d312af0c);
+    pub const CHAR_CMD: Uuid = Uuid::from_u128(0xf0cd140295da4f4b9ac8aa55d312af0c);
+    pub const CHAR_CALIBRATION_DATA: Uuid = Uuid::from_u128(0xf0cd150295da4f4b9ac8aa55d312af0c);
+    pub const CHAR_CURRENT_READINGS: Uuid = Uuid::from_u128(0xf0cd150395da4f4b9ac8aa55d312af0c);
+    pub const CHAR_CURRENT_READINGS_AR2: Uuid = Uuid::from_u128(0xf0cd150495da4f4b9ac8aa55d312af0c); // Aranet2 Only
+    pub const CHAR_TOTAL_READINGS: Uuid = Uuid::from_u128(0xf0cd200195da4f4b9ac8aa55d312af0c);
+    pub const CHAR_INTERVAL: Uuid = Uuid::from_u128(0xf0cd200295da4f4b9ac8aa55d312af0c);
+    pub const CHAR_HISTORY_READINGS_V1: Uuid = Uuid::from_u128(0xf0cd200395da4f4b9ac8aa55d312af0c);
+    pub const CHAR_SECONDS_SINCE_UPDATE: Uuid = Uuid::from_u128(0xf0cd200495da4f4b9ac8aa55d312af0c);
+    pub const CHAR_HISTORY_READINGS_V2: Uuid = Uuid::from_u128(0xf0cd200595da4f4b9ac8aa55d312af0c);
+    pub const CHAR_CURRENT_READINGS_DET: Uuid = Uuid::from_u128(0xf0cd300195da4f4b9ac8aa55d312af0c);
+    pub const CHAR_CURRENT_READINGS_A: Uuid = Uuid::from_u128(0xf0cd300295da4f4b9ac8aa55d312af0c);
+    pub const CHAR_CURRENT_READINGS_A_AR2: Uuid = Uuid::from_u128(0xf0cd300395da4f4b9ac8aa55d312af0c); // Aranet2 Only
+
+    pub const SERVICE_NORDIC_SEMICONDUCTOR: Uuid = Uuid::from_u128(0x0000fe5900001000800000805f9b34fb);
+    pub const CHAR_SECURE_DFU: Uuid = Uuid::from_u128(0x8ec90003f3154f609fb8838830daea50);
 }
 
-#[allow(dead_code)]
 #[rustfmt::skip]
-pub async fn map_device_endpoints(dev: &Device) -> Result<EndPoints> {
-    const SERVICE_GAP: Uuid = Uuid::from_u128(0x0000180000001000800000805f9b34fb);
-    const CHAR_DEVICE_NAME: Uuid = Uuid::from_u128(0x00002a000001000800000805f9b34fb);
-    const CHAR_APPEARANCE: Uuid = Uuid::from_u128(0x00002a010001000800000805f9b34fb);
-
-    const SERVICE_DIS: Uuid = Uuid::from_u128(0x0000180a00001000800000805f9b34fb);
-    const CHAR_SYSTEM_ID: Uuid = Uuid::from_u128(0x00002a230001000800000805f9b34fb);
-    const CHAR_MODEL_NUMBER: Uuid = Uuid::from_u128(0x00002a240001000800000805f9b34fb);
-    const CHAR_SERIAL_NO: Uuid = Uuid::from_u128(0x00002a250001000800000805f9b34fb);
-    const CHAR_SW_REV: Uuid = Uuid::from_u128(0x00002a260001000800000805f9b34fb);
-    const CHAR_HW_REV: Uuid = Uuid::from_u128(0x00002a270001000800000805f9b34fb);
-    const CHAR_SW_REV_FACTORY: Uuid = Uuid::from_u128(0x00002a280001000800000805f9b34fb);
-    const CHAR_MANUFACTURER_NAME: Uuid = Uuid::from_u128(0x00002a290001000800000805f9b34fb);
-
-    const SERVICE_BATTTERY: Uuid = Uuid::from_u128(0x0000180f00001000800000805f9b34fb); // v1.2.0 and later
-    const CHAR_BATTERY_LEVEL: Uuid = Uuid::from_u128(0x00002a190001000800000805f9b34fb);
-
-    const SERVICE_SAF_TEHNIKA: Uuid = Uuid::from_u128(0x0000fce000001000800000805f9b34fb); // v1.2.0 and later
-    const CHAR_SENSOR_STATE: Uuid = Uuid::from_u128(0xf0cd140195da4f4b9ac8aa55d312af0c);
-    const CHAR_CMD: Uuid = Uuid::from_u128(0xf0cd140295da4f4b9ac8aa55d312af0c);
-    const CHAR_CALIBRATION_DATA: Uuid = Uuid::from_u128(0xf0cd150295da4f4b9ac8aa55d312af0c);
-    const CHAR_CURRENT_READINGS: Uuid = Uuid::from_u128(0xf0cd150395da4f4b9ac8aa55d312af0c);
-    const CHAR_CURRENT_READINGS_AR2: Uuid = Uuid::from_u128(0xf0cd150495da4f4b9ac8aa55d312af0c); // Aranet2 Only
-    const CHAR_TOTAL_READINGS: Uuid = Uuid::from_u128(0xf0cd200195da4f4b9ac8aa55d312af0c);
-    const CHAR_INTERVAL: Uuid = Uuid::from_u128(0xf0cd200295da4f4b9ac8aa55d312af0c);
-    const CHAR_HISTORY_READINGS_V1: Uuid = Uuid::from_u128(0xf0cd200395da4f4b9ac8aa55d312af0c);
-    const CHAR_SECONDS_SINCE_UPDATE: Uuid = Uuid::from_u128(0xf0cd200495da4f4b9ac8aa55d312af0c);
-    const CHAR_HISTORY_READINGS_V2: Uuid = Uuid::from_u128(0xf0cd200595da4f4b9ac8aa55d312af0c);
-    const CHAR_CURRENT_READINGS_DET: Uuid = Uuid::from_u128(0xf0cd300195da4f4b9ac8aa55d312af0c);
-    const CHAR_CURRENT_READINGS_A: Uuid = Uuid::from_u128(0xf0cd300295da4f4b9ac8aa55d312af0c);
-    const CHAR_CURRENT_READINGS_A_AR2: Uuid = Uuid::from_u128(0xf0cd300395da4f4b9ac8aa55d312af0c); // Aranet2 Only
-
-    const SERVICE_NORDIC_SEMICONDUCTOR: Uuid = Uuid::from_u128(0x0000fe5900001000800000805f9b34fb);
-    const CHAR_SECURE_DFU: Uuid = Uuid::from_u128(0x8ec90003f3154f609fb8838830daea50);
+pub async fn map_device_endpoints<P: Peripheral>(dev: &P) -> Result<EndPoints<P::Characteristic>> {
+    use uuids::*;
 
     let mut endpoint = EndPoints::default();
 
-    for service in dev.services().await? {
-        let service_uuid = service.uuid().await?;
-        for characteristic in service.characteristics().await? {
-            let characteristic_uuid = characteristic.uuid().await?;
-            match (service_uuid, characteristic_uuid) {
-                (SERVICE_BATTTERY, CHAR_BATTERY_LEVEL) => endpoint.battery_level = Some(characteristic),
-                (SERVICE_SAF_TEHNIKA, CHAR_SENSOR_STATE) => endpoint.sensor_state = Some(characteristic),
-                (SERVICE_SAF_TEHNIKA, CHAR_CMD) => endpoint.cmd = Some(characteristic),
-                (SERVICE_SAF_TEHNIKA, CHAR_CALIBRATION_DATA) => endpoint.calibration_data = Some(characteristic),
-                (SERVICE_SAF_TEHNIKA, CHAR_CURRENT_READINGS) => endpoint.current_readings = Some(characteristic),
-                (SERVICE_SAF_TEHNIKA, CHAR_CURRENT_READINGS_AR2) => endpoint.current_readings_ar2 = Some(characteristic),
-                (SERVICE_SAF_TEHNIKA, CHAR_TOTAL_READINGS) => endpoint.total_readings = Some(characteristic),
-                (SERVICE_SAF_TEHNIKA, CHAR_INTERVAL) => endpoint.interval = Some(characteristic),
-                (SERVICE_SAF_TEHNIKA, CHAR_HISTORY_READINGS_V1) => endpoint.history_readings_v1 = Some(characteristic),
-                (SERVICE_SAF_TEHNIKA, CHAR_SECONDS_SINCE_UPDATE) => endpoint.seconds_since_update = Some(characteristic),
-                (SERVICE_SAF_TEHNIKA, CHAR_HISTORY_READINGS_V2) => endpoint.history_readings_v2 = Some(characteristic),
-                (SERVICE_SAF_TEHNIKA, CHAR_CURRENT_READINGS_DET) => endpoint.current_readings_det = Some(characteristic),
-                (SERVICE_SAF_TEHNIKA, CHAR_CURRENT_READINGS_A) => endpoint.current_readings_a = Some(characteristic),
-                (SERVICE_SAF_TEHNIKA, CHAR_CURRENT_READINGS_A_AR2) => endpoint.current_readings_a_ar2 = Some(characteristic),
-                (_, _) => {},
-            }
+    for (service_uuid, characteristic_uuid, characteristic) in dev.characteristics().await? {
+        match (service_uuid, characteristic_uuid) {
+            (SERVICE_DIS, CHAR_MODEL_NUMBER) => endpoint.model_number = Some(characteristic),
+            (SERVICE_DIS, CHAR_SERIAL_NO) => endpoint.serial_no = Some(characteristic),
+            (SERVICE_DIS, CHAR_SW_REV) => endpoint.firmware_rev = Some(characteristic),
+            (SERVICE_DIS, CHAR_HW_REV) => endpoint.hardware_rev = Some(characteristic),
+            (SERVICE_DIS, CHAR_SW_REV_FACTORY) => endpoint.software_rev = Some(characteristic),
+            (SERVICE_DIS, CHAR_MANUFACTURER_NAME) => endpoint.manufacturer_name = Some(characteristic),
+            (SERVICE_BATTTERY, CHAR_BATTERY_LEVEL) => endpoint.battery_level = Some(characteristic),
+            (SERVICE_SAF_TEHNIKA, CHAR_SENSOR_STATE) => endpoint.sensor_state = Some(characteristic),
+            (SERVICE_SAF_TEHNIKA, CHAR_CMD) => endpoint.cmd = Some(characteristic),
+            (SERVICE_SAF_TEHNIKA, CHAR_CALIBRATION_DATA) => endpoint.calibration_data = Some(characteristic),
+            (SERVICE_SAF_TEHNIKA, CHAR_CURRENT_READINGS) => endpoint.current_readings = Some(characteristic),
+            (SERVICE_SAF_TEHNIKA, CHAR_CURRENT_READINGS_AR2) => endpoint.current_readings_ar2 = Some(characteristic),
+            (SERVICE_SAF_TEHNIKA, CHAR_TOTAL_READINGS) => endpoint.total_readings = Some(characteristic),
+            (SERVICE_SAF_TEHNIKA, CHAR_INTERVAL) => endpoint.interval = Some(characteristic),
+            (SERVICE_SAF_TEHNIKA, CHAR_HISTORY_READINGS_V1) => endpoint.history_readings_v1 = Some(characteristic),
+            (SERVICE_SAF_TEHNIKA, CHAR_SECONDS_SINCE_UPDATE) => endpoint.seconds_since_update = Some(characteristic),
+            (SERVICE_SAF_TEHNIKA, CHAR_HISTORY_READINGS_V2) => endpoint.history_readings_v2 = Some(characteristic),
+            (SERVICE_SAF_TEHNIKA, CHAR_CURRENT_READINGS_DET) => endpoint.current_readings_det = Some(characteristic),
+            (SERVICE_SAF_TEHNIKA, CHAR_CURRENT_READINGS_A) => endpoint.current_readings_a = Some(characteristic),
+            (SERVICE_SAF_TEHNIKA, CHAR_CURRENT_READINGS_A_AR2) => endpoint.current_readings_a_ar2 = Some(characteristic),
+            (_, _) => {},
         }
     }
 
@@ -0,0 +1,96 @@
+//! Parquet export of samples, one row per sample with the same field names and units as the
+//! JSON output.
+
+use std::{
+    fs::{self, File},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use arrow_array::{
+    ArrayRef, Float64Array, RecordBatch, StringArray, TimestampSecondArray, UInt16Array,
+    UInt32Array, UInt8Array,
+};
+use arrow_schema::{DataType, Field, Schema, SchemaRef, TimeUnit};
+use parquet::{arrow::ArrowWriter, basic::Compression, file::properties::WriterProperties};
+
+use crate::{types::Sample, Result};
+
+pub fn schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        Field::new(
+            "time",
+            DataType::Timestamp(TimeUnit::Second, Some("UTC".into())),
+            false,
+        ),
+        Field::new("device", DataType::Utf8, false),
+        Field::new("model", DataType::Utf8, true),
+        Field::new("co2_ppm", DataType::UInt16, true),
+        Field::new("temperature_c", DataType::Float64, true),
+        Field::new("humidity_percent", DataType::Float64, true),
+        Field::new("pressure_hpa", DataType::Float64, true),
+        Field::new("battery_percent", DataType::UInt8, true),
+        Field::new("radon_bq_m3", DataType::UInt32, true),
+        Field::new("dose_rate_nsv_h", DataType::UInt32, true),
+        Field::new("dose_nsv", DataType::UInt32, true),
+    ]))
+}
+
+pub fn record_batch(samples: &[Sample]) -> Result<RecordBatch> {
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(
+            TimestampSecondArray::from_iter_values(samples.iter().map(|x| x.time.timestamp()))
+                .with_timezone("UTC"),
+        ),
+        Arc::new(StringArray::from_iter_values(
+            samples.iter().map(|x| &x.device),
+        )),
+        Arc::new(StringArray::from_iter(
+            samples.iter().map(|x| x.model.map(|x| x.to_string())),
+        )),
+        Arc::new(UInt16Array::from_iter(samples.iter().map(|x| x.c02))),
+        Arc::new(Float64Array::from_iter(samples.iter().map(|x| x.temp))),
+        Arc::new(Float64Array::from_iter(samples.iter().map(|x| x.humidity))),
+        Arc::new(Float64Array::from_iter(samples.iter().map(|x| x.preasure))),
+        Arc::new(UInt8Array::from_iter(samples.iter().map(|x| x.bat))),
+        Arc::new(UInt32Array::from_iter(samples.iter().map(|x| x.radon))),
+        Arc::new(UInt32Array::from_iter(
+            samples.iter().map(|x| x.dose_rate_nsv_h()),
+        )),
+        Arc::new(UInt32Array::from_iter(samples.iter().map(|x| x.dose_nsv()))),
+    ];
+    Ok(RecordBatch::try_new(schema(), columns)?)
+}
+
+/// Writes a single snappy compressed file
+pub fn write_parquet<W: Write + Send>(w: W, samples: &[Sample]) -> Result<()> {
+    let props = WriterProperties::builder()
+        .set_compression(Compression::SNAPPY)
+        .build();
+    let mut writer = ArrowWriter::try_new(w, schema(), Some(props))?;
+    writer.write(&record_batch(samples)?)?;
+    writer.close()?;
+    Ok(())
+}
+
+/// Writes a hive style `device=<address>/date=<YYYY-MM-DD>/data.parquet` tree under `dir`, days
+/// are UTC and files already there are replaced. Returns the files written.
+pub fn write_partitioned(dir: &Path, samples: &[Sample]) -> Result<Vec<PathBuf>> {
+    let mut samples = samples.to_vec();
+    samples.sort_by(|a, b| (&a.device, a.time).cmp(&(&b.device, b.time)));
+
+    let mut written = Vec::new();
+    for part in
+        samples.chunk_by(|a, b| a.device == b.device && a.time.date_naive() == b.time.date_naive())
+    {
+        let dir = dir
+            .join(format!("device={}", part[0].device))
+            .join(format!("date={}", part[0].time.date_naive()));
+        fs::create_dir_all(&dir)?;
+        let path = dir.join("data.parquet");
+        write_parquet(File::create(&path)?, part)?;
+        written.push(path);
+    }
+    Ok(written)
+}
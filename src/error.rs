@@ -0,0 +1,57 @@
+use thiserror::Error;
+
+use crate::pinentry::AssuanError;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Everything the library can fail with, the binary turns these into actionable messages
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Bluetooth adapter {0} not found")]
+    AdapterMissing(String),
+    #[error("Device not found: {0}")]
+    DeviceNotFound(String),
+    #[error("Pairing with {address} was rejected: {reason}")]
+    PairingRejected { address: String, reason: String },
+    #[error("Missing characteristic: {0}")]
+    MissingCharacteristic(&'static str),
+    #[error("Short read from {name}: {len} bytes, expected {expected}")]
+    ShortPayload {
+        name: &'static str,
+        len: usize,
+        expected: usize,
+    },
+    #[error("Malformed {name}: {reason}")]
+    Malformed { name: &'static str, reason: String },
+    /// A command was written but reading back showed it had no effect
+    #[error("Device didn't apply {0}")]
+    NotApplied(String),
+    #[error("{0}")]
+    Unsupported(String),
+    #[error("Timed out {0}")]
+    Timeout(&'static str),
+    #[error("Config: {0}")]
+    Config(String),
+    #[error(transparent)]
+    Pinentry(#[from] AssuanError),
+    #[error("Bluetooth: {0}")]
+    Bluetooth(#[from] bluer::Error),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("Store: {0}")]
+    Store(#[from] rusqlite::Error),
+    #[error("HTTP: {0}")]
+    Http(String),
+    #[error("MQTT: {0}")]
+    Mqtt(String),
+    #[error("CSV: {0}")]
+    Csv(#[from] csv::Error),
+    #[error("Parquet: {0}")]
+    Parquet(#[from] parquet::errors::ParquetError),
+    #[error("Arrow: {0}")]
+    Arrow(#[from] arrow_schema::ArrowError),
+    #[error("JSON: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Metrics: {0}")]
+    Prometheus(#[from] prometheus::Error),
+}
@@ -0,0 +1,287 @@
+//! InfluxDB line protocol writer, for setups running Influx rather than scraping Prometheus.
+//! Points are spooled to disk until the server took them, so nothing is lost while it's
+//! unreachable. Run it through `sink::Batched`, requests block.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{self, OpenOptions},
+    io::Write,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    metric::DeviceLabels, schedule::Backoff, sink::BatchWriter, types::Sample, Error, Result,
+};
+
+/// Influx recommends batches of around 5000 lines
+pub const DEFAULT_BATCH_SIZE: usize = 5000;
+
+/// Which write endpoint and authentication to use
+#[derive(Debug, Clone)]
+pub enum Api {
+    /// `/write`, optionally with basic auth
+    V1 {
+        database: String,
+        username: Option<String>,
+        password: Option<String>,
+    },
+    /// `/api/v2/write` with a token
+    V2 {
+        org: String,
+        bucket: String,
+        token: String,
+    },
+}
+
+pub struct Influx {
+    agent: ureq::Agent,
+    base_url: String,
+    api: Api,
+    measurement: String,
+    /// Added to every point
+    tags: BTreeMap<String, String>,
+    /// `name` and `room` by device address
+    devices: HashMap<String, DeviceLabels>,
+    batch_size: usize,
+    /// Lines not taken by the server yet, oldest first
+    pending: Vec<String>,
+    /// Mirrors `pending` when set
+    buffer: Option<PathBuf>,
+    backoff: Backoff,
+    retry_at: Option<Instant>,
+}
+
+impl Influx {
+    pub fn new(base_url: &str, api: Api) -> Self {
+        Self {
+            agent: ureq::AgentBuilder::new()
+                .timeout(Duration::from_secs(10))
+                .build(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            api,
+            measurement: "aranet".to_string(),
+            tags: BTreeMap::new(),
+            devices: HashMap::new(),
+            batch_size: DEFAULT_BATCH_SIZE,
+            pending: Vec::new(),
+            buffer: None,
+            backoff: Backoff::new(Duration::from_secs(5), Duration::from_secs(5 * 60)),
+            retry_at: None,
+        }
+    }
+
+    pub fn set_measurement(&mut self, measurement: &str) {
+        self.measurement = measurement.to_string();
+    }
+
+    pub fn set_tags(&mut self, tags: BTreeMap<String, String>) {
+        self.tags = tags;
+    }
+
+    /// Tags points of `labels.address` with it's name and room
+    pub fn set_labels(&mut self, labels: &DeviceLabels) {
+        self.devices.insert(labels.address.clone(), labels.clone());
+    }
+
+    /// Most lines sent in a single request, a flush starts once this many are waiting
+    pub fn set_batch_size(&mut self, batch_size: usize) {
+        self.batch_size = batch_size.max(1);
+    }
+
+    /// Spools pending points to `path`, picking up whatever an earlier run left there
+    pub fn set_buffer(&mut self, path: impl Into<PathBuf>) -> Result<()> {
+        let path = path.into();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        if path.exists() {
+            let spooled = fs::read_to_string(&path)?;
+            let mut lines: Vec<String> = spooled
+                .lines()
+                .filter(|x| !x.is_empty())
+                .map(str::to_string)
+                .collect();
+            lines.append(&mut self.pending);
+            self.pending = lines;
+        }
+        self.buffer = Some(path);
+        self.save()?;
+        Ok(())
+    }
+
+    /// A sample as a line of line protocol, `None` when it has no values
+    pub fn line(&self, sample: &Sample) -> Option<String> {
+        let mut fields = Vec::new();
+        let mut int = |key: &str, x: Option<u64>| {
+            if let Some(x) = x {
+                fields.push(format!("{key}={x}i"));
+            }
+        };
+        int("co2_ppm", sample.c02.map(u64::from));
+        int("battery_percent", sample.bat.map(u64::from));
+        int("radon_bq_m3", sample.radon.map(u64::from));
+        int("dose_rate_nsv_h", sample.dose_rate_nsv_h().map(u64::from));
+        int("dose_nsv", sample.dose_nsv().map(u64::from));
+        for (key, x) in [
+            ("temperature_c", sample.temp),
+            ("humidity_percent", sample.humidity),
+            ("pressure_hpa", sample.preasure),
+        ] {
+            if let Some(x) = x.filter(|x| x.is_finite()) {
+                fields.push(format!("{key}={x}"));
+            }
+        }
+        if fields.is_empty() {
+            return None;
+        }
+
+        let mut tags = self.tags.clone();
+        tags.insert("device".to_string(), sample.device.clone());
+        if let Some(model) = sample.model {
+            tags.insert("model".to_string(), model.to_string());
+        }
+        if let Some(labels) = self.devices.get(&sample.device) {
+            for (key, value) in [("name", &labels.name), ("room", &labels.room)] {
+                if !value.is_empty() {
+                    tags.insert(key.to_string(), value.clone());
+                }
+            }
+        }
+
+        let mut line = escape(&self.measurement, &[',', ' ']);
+        for (key, value) in &tags {
+            line += &format!(
+                ",{}={}",
+                escape(key, &[',', '=', ' ']),
+                escape(value, &[',', '=', ' '])
+            );
+        }
+        line += &format!(" {} {}", fields.join(","), sample.time.timestamp());
+        Some(line)
+    }
+
+    fn post(&self, body: &str) -> std::result::Result<(), Box<ureq::Error>> {
+        let request = match &self.api {
+            Api::V1 {
+                database,
+                username,
+                password,
+            } => {
+                let mut request = self
+                    .agent
+                    .post(&format!("{}/write", self.base_url))
+                    .query("db", database)
+                    .query("precision", "s");
+                if let (Some(u), Some(p)) = (username, password) {
+                    request = request.query("u", u).query("p", p);
+                }
+                request
+            }
+            Api::V2 { org, bucket, token } => self
+                .agent
+                .post(&format!("{}/api/v2/write", self.base_url))
+                .query("org", org)
+                .query("bucket", bucket)
+                .query("precision", "s")
+                .set("Authorization", &format!("Token {token}")),
+        };
+        request
+            .set("Content-Type", "text/plain; charset=utf-8")
+            .send_string(body)?;
+        Ok(())
+    }
+
+    /// Rewrites the spool file to match `pending`
+    fn save(&self) -> Result<()> {
+        if let Some(path) = &self.buffer {
+            let mut contents = self.pending.join("\n");
+            if !contents.is_empty() {
+                contents.push('\n');
+            }
+            fs::write(path, contents)?;
+        }
+        Ok(())
+    }
+
+    fn spool(&mut self, lines: Vec<String>) -> Result<()> {
+        if let Some(path) = &self.buffer {
+            let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+            for line in &lines {
+                writeln!(file, "{line}")?;
+            }
+        }
+        self.pending.extend(lines);
+        Ok(())
+    }
+}
+
+impl BatchWriter for Influx {
+    /// Spooled to disk right away
+    fn queue(&mut self, samples: &[Sample]) -> Result<()> {
+        let lines: Vec<String> = samples.iter().filter_map(|x| self.line(x)).collect();
+        self.spool(lines)
+    }
+
+    fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    fn batch_size(&self) -> usize {
+        self.batch_size
+    }
+
+    /// Sends everything pending in batches of `batch_size`. Batches the server rejects as
+    /// malformed are dropped, anything else is kept for the next try.
+    fn flush(&mut self) -> Result<usize> {
+        let mut sent = 0;
+        let res = loop {
+            if self.pending.is_empty() {
+                break Ok(sent);
+            }
+            let n = self.batch_size.min(self.pending.len());
+            match self.post(&self.pending[..n].join("\n")).map_err(|e| *e) {
+                Ok(()) => sent += n,
+                Err(ureq::Error::Status(code @ (400 | 422), response)) => {
+                    let body = response.into_string().unwrap_or_default();
+                    eprintln!("influx: dropping {n} points the server rejected ({code}): {body}");
+                }
+                Err(e) => break Err(Error::Http(describe(e))),
+            }
+            self.pending.drain(..n);
+        };
+        self.save()?;
+        match res {
+            Ok(_) => {
+                self.backoff.reset();
+                self.retry_at = None;
+            }
+            Err(_) => self.retry_at = Some(Instant::now() + self.backoff.next_delay()),
+        }
+        res
+    }
+
+    fn retry_at(&self) -> Option<Instant> {
+        self.retry_at
+    }
+}
+
+fn escape(s: &str, special: &[char]) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if special.contains(&c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn describe(e: ureq::Error) -> String {
+    match e {
+        ureq::Error::Status(code, response) => {
+            format!("{code}: {}", response.into_string().unwrap_or_default())
+        }
+        ureq::Error::Transport(e) => e.to_string(),
+    }
+}
@@ -1,3 +1,17 @@
+pub mod aranet_home;
 pub mod bluetooth;
+pub mod columnar;
+mod error;
+pub mod influx;
 pub mod metric;
+pub mod mock;
+pub mod mqtt;
+pub mod pinentry;
+pub mod remote_write;
+pub mod schedule;
+pub mod sink;
+pub mod store;
+pub mod transport;
 pub mod types;
+
+pub use error::{Error, Result};
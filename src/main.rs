@@ -1,33 +1,330 @@
-use std::{env, fs, net::ToSocketAddrs, time::Duration};
+use std::{
+    collections::{BTreeMap, HashMap},
+    env, fs, io,
+    net::ToSocketAddrs,
+    path::PathBuf,
+    process,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
-use anyhow::Result;
-use bluer::{agent::Agent, AdapterEvent, Address, Device};
-use clap::{Parser, Subcommand};
+use bluer::{agent::Agent, Adapter, AdapterEvent, Address, Device, DeviceEvent, DeviceProperty};
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, TimeDelta, Utc};
+use clap::{builder::BoolishValueParser, Args, Parser, Subcommand, ValueEnum};
 use futures::prelude::*;
-use prometheus::{register, Gauge, IntGauge};
-use serde::{de::DeserializeOwned, Deserialize};
+use rumqttc::{MqttOptions, TlsConfiguration, Transport};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
-use aranet::{bluetooth::*, metric};
-use tokio::time::timeout;
+use aranet::{
+    aranet_home,
+    bluetooth::*,
+    columnar,
+    influx::{self, Influx},
+    metric,
+    mqtt::{self, Mqtt},
+    remote_write::RemoteWrite,
+    schedule::{self, Backoff, PollStats},
+    sink::{self, BatchWriter, Batched, Sink},
+    store::Store,
+    types::{BluetoothRange, DeviceModel, HistoryRecord, Interval, Reading, Sample, TempUnit},
+    Error, Result,
+};
+use tokio::{sync::mpsc::UnboundedSender, task::JoinSet, time::timeout};
+
+#[derive(Deserialize, Clone)]
+pub struct DeviceCfg {
+    /// FORMAT: ED:12:89:6C:08:37
+    pub mac: String,
+    pub name: Option<String>,
+    pub room: Option<String>,
+}
 
 #[derive(Deserialize)]
 pub struct Cfg {
     /// EX: hci0
     pub adapter: String,
     /// FORMAT: ED:12:89:6C:08:37
+    #[serde(default)]
     pub macs: Vec<String>,
+    /// Devices with a friendly name and/or room, these are polled alongside `macs`
+    #[serde(default)]
+    pub devices: Vec<DeviceCfg>,
     pub fahrenheit: Option<bool>,
     // Seconds
     pub stream_freq: Option<u64>,
     pub prometheus_address: Option<String>,
     pub conn_timeout_ms: Option<u64>,
+    /// Used for pairing when neither pinentry nor a terminal is available, ARANET_PASSKEY
+    /// takes precedence
+    pub passkey: Option<String>,
+    /// Program used for pin entry, EX: pinentry-curses
+    pub pinentry: Option<String>,
+    /// SQLite database `service` and `history` write to, defaults to
+    /// ~/.local/share/aranet/aranet.db
+    pub database: Option<String>,
+    /// `service` also writes readings to InfluxDB when set
+    pub influx: Option<InfluxCfg>,
+    /// `service` also publishes readings to an MQTT broker when set
+    pub mqtt: Option<MqttCfg>,
+    /// `service` also sends readings to a Prometheus remote-write endpoint when set
+    pub remote_write: Option<RemoteWriteCfg>,
+}
+
+#[derive(Deserialize)]
+pub struct MqttCfg {
+    pub host: String,
+    /// Defaults to 1883, or 8883 with tls
+    pub port: Option<u16>,
+    /// Defaults to aranet
+    pub client_id: Option<String>,
+    pub username: Option<String>,
+    /// ARANET_MQTT_PASSWORD takes precedence
+    pub password: Option<String>,
+    #[serde(default)]
+    pub tls: bool,
+    /// PEM CA certificates, defaults to /etc/ssl/certs/ca-certificates.crt
+    pub ca: Option<String>,
+    /// State topic template, defaults to aranet/{device}
+    pub topic: Option<String>,
+    /// Defaults to aranet/status
+    pub availability_topic: Option<String>,
+    /// Retain state messages, defaults to true
+    pub retain: Option<bool>,
+    /// Home Assistant discovery prefix, defaults to homeassistant, an empty one turns
+    /// discovery off
+    pub discovery_prefix: Option<String>,
+}
+
+impl MqttCfg {
+    fn connect(&self, cfg: &Cfg) -> Result<Mqtt> {
+        let port = self.port.unwrap_or(if self.tls { 8883 } else { 1883 });
+        let mut options = MqttOptions::new(
+            self.client_id.as_deref().unwrap_or("aranet"),
+            &self.host,
+            port,
+        );
+        options.set_keep_alive(Duration::from_secs(30));
+        let password = env::var("ARANET_MQTT_PASSWORD")
+            .ok()
+            .or(self.password.clone());
+        if let Some(username) = &self.username {
+            options.set_credentials(username, password.unwrap_or_default());
+        }
+        if self.tls {
+            let path = self
+                .ca
+                .as_deref()
+                .unwrap_or("/etc/ssl/certs/ca-certificates.crt");
+            let ca = fs::read(path)
+                .map_err(|e| Error::Config(format!("reading mqtt.ca {path}: {e}")))?;
+            options.set_transport(Transport::Tls(TlsConfiguration::Simple {
+                ca,
+                alpn: None,
+                client_auth: None,
+            }));
+        }
+
+        let discovery = self.discovery_prefix.as_deref().unwrap_or("homeassistant");
+        let mut topics = mqtt::Topics::new(
+            self.topic.as_deref().unwrap_or("aranet/{device}"),
+            self.availability_topic
+                .as_deref()
+                .unwrap_or("aranet/status"),
+            Some(discovery).filter(|x| !x.is_empty()),
+        );
+        for address in cfg.addresses()? {
+            topics.set_labels(&cfg.labels(address));
+        }
+        let mut publisher = mqtt::Publisher::new(topics);
+        publisher.retain = self.retain.unwrap_or(true);
+        Ok(Mqtt::start(options, publisher))
+    }
+}
+
+/// Either `bucket`, `org` and `token` for InfluxDB 2, or `database` for 1.x
+#[derive(Deserialize)]
+pub struct InfluxCfg {
+    /// EX: http://localhost:8086
+    pub url: String,
+    pub org: Option<String>,
+    pub bucket: Option<String>,
+    /// ARANET_INFLUX_TOKEN takes precedence
+    pub token: Option<String>,
+    pub database: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Defaults to aranet
+    pub measurement: Option<String>,
+    /// Added to every point, alongside device, model, name and room
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+    /// Points sent per request, a flush starts once this many are waiting. Defaults to 5000
+    pub batch_size: Option<usize>,
+    /// Seconds between flushes, defaults to 10
+    pub flush_interval: Option<u64>,
+    /// Where points wait while the server is unreachable, defaults to
+    /// ~/.local/share/aranet/influx.buffer
+    pub buffer: Option<String>,
+}
+
+impl InfluxCfg {
+    fn api(&self) -> Result<influx::Api> {
+        let token = env::var("ARANET_INFLUX_TOKEN").ok().or(self.token.clone());
+        match (&self.bucket, &self.database) {
+            (Some(bucket), _) => Ok(influx::Api::V2 {
+                org: self.org.clone().ok_or(Error::Config(
+                    "influx.org is needed with bucket".to_string(),
+                ))?,
+                bucket: bucket.clone(),
+                token: token.ok_or(Error::Config(
+                    "influx.token or ARANET_INFLUX_TOKEN is needed with bucket".to_string(),
+                ))?,
+            }),
+            (None, Some(database)) => Ok(influx::Api::V1 {
+                database: database.clone(),
+                username: self.username.clone(),
+                password: self.password.clone(),
+            }),
+            (None, None) => Err(Error::Config(
+                "influx needs either bucket or database".to_string(),
+            )),
+        }
+    }
+
+    fn connect(&self, cfg: &Cfg) -> Result<Batched> {
+        let mut influx = Influx::new(&self.url, self.api()?);
+        if let Some(measurement) = &self.measurement {
+            influx.set_measurement(measurement);
+        }
+        influx.set_tags(self.tags.clone());
+        if let Some(batch_size) = self.batch_size {
+            influx.set_batch_size(batch_size);
+        }
+        for address in cfg.addresses()? {
+            influx.set_labels(&cfg.labels(address));
+        }
+        influx.set_buffer(match &self.buffer {
+            Some(path) => path.clone(),
+            None => data_file("influx.buffer")?,
+        })?;
+        if influx.pending() > 0 {
+            eprintln!("influx: {} buffered points to send", influx.pending());
+        }
+        let interval = Duration::from_secs(self.flush_interval.unwrap_or(10));
+        Ok(Batched::start("influx", influx, interval))
+    }
+}
+
+#[derive(Deserialize)]
+pub struct RemoteWriteCfg {
+    /// EX: http://localhost:9090/api/v1/write
+    pub url: String,
+    pub username: Option<String>,
+    /// ARANET_REMOTE_WRITE_PASSWORD takes precedence
+    pub password: Option<String>,
+    /// Sent as a bearer token instead of basic auth
+    pub bearer_token: Option<String>,
+    /// Added to every series, alongside address, name and room
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    /// Samples sent per request, a flush starts once this many are waiting. Defaults to 500
+    pub batch_size: Option<usize>,
+    /// Seconds between flushes, defaults to 10
+    pub flush_interval: Option<u64>,
+}
+
+impl RemoteWriteCfg {
+    fn connect(&self, cfg: &Cfg) -> Result<Batched> {
+        let mut remote = RemoteWrite::new(&self.url);
+        let password = env::var("ARANET_REMOTE_WRITE_PASSWORD")
+            .ok()
+            .or(self.password.clone());
+        match (&self.bearer_token, &self.username) {
+            (Some(token), _) => remote.set_bearer_token(token),
+            (None, Some(username)) => {
+                remote.set_basic_auth(username, &password.unwrap_or_default())
+            }
+            (None, None) => {}
+        }
+        remote.set_external_labels(self.labels.clone());
+        if let Some(batch_size) = self.batch_size {
+            remote.set_batch_size(batch_size);
+        }
+        for address in cfg.addresses()? {
+            remote.set_labels(&cfg.labels(address));
+        }
+        let interval = Duration::from_secs(self.flush_interval.unwrap_or(10));
+        Ok(Batched::start("remote_write", remote, interval))
+    }
+}
+
+/// A file under ~/.local/share/aranet
+fn data_file(name: &str) -> Result<String> {
+    let home = env::var("HOME").map_err(|_| Error::Config("HOME isn't set".to_string()))?;
+    Ok(format!("{home}/.local/share/aranet/{name}"))
+}
+
+impl Cfg {
+    pub fn addresses(&self) -> Result<Vec<Address>> {
+        let mut addresses: Vec<Address> = self
+            .macs
+            .iter()
+            .chain(self.devices.iter().map(|x| &x.mac))
+            .map(|x| str_mac_to_array(x).map(Address::new))
+            .collect::<Result<_>>()?;
+        addresses.sort();
+        addresses.dedup();
+        Ok(addresses)
+    }
+
+    pub fn database(&self) -> Result<String> {
+        match &self.database {
+            Some(path) => Ok(path.clone()),
+            None => data_file("aranet.db"),
+        }
+    }
+
+    /// Address of a configured device name, anything else is taken as an address
+    pub fn resolve(&self, device: &str) -> Result<Address> {
+        let mac = self
+            .devices
+            .iter()
+            .find(|x| x.name.as_deref() == Some(device))
+            .map_or(device, |x| &x.mac);
+        Ok(Address::new(str_mac_to_array(mac)?))
+    }
+
+    /// Configured addresses for error messages
+    fn describe_devices(&self) -> String {
+        let macs: Vec<&str> = self
+            .macs
+            .iter()
+            .chain(self.devices.iter().map(|x| &x.mac))
+            .map(String::as_str)
+            .collect();
+        macs.join(", ")
+    }
+
+    pub fn labels(&self, address: Address) -> metric::DeviceLabels {
+        let dev = self
+            .devices
+            .iter()
+            .find(|x| str_mac_to_array(&x.mac).is_ok_and(|mac| Address::new(mac) == address));
+
+        metric::DeviceLabels {
+            address: address.to_string(),
+            name: dev.and_then(|x| x.name.clone()).unwrap_or_default(),
+            room: dev.and_then(|x| x.room.clone()).unwrap_or_default(),
+        }
+    }
 }
 
 pub fn try_get_cfg<T: DeserializeOwned>() -> Result<T> {
-    let home = env::var("HOME")?;
-    let content = fs::read_to_string(format!("{home}/.config/aranet/config.toml"))?;
-    let config = toml::from_str::<T>(&content)?;
-    Ok(config)
+    let home = env::var("HOME").map_err(|_| Error::Config("HOME isn't set".to_string()))?;
+    let path = format!("{home}/.config/aranet/config.toml");
+    let content =
+        fs::read_to_string(&path).map_err(|e| Error::Config(format!("reading {path}: {e}")))?;
+    toml::from_str::<T>(&content).map_err(|e| Error::Config(format!("{path}: {e}")))
 }
 
 #[derive(Debug, Parser)]
@@ -35,6 +332,51 @@ pub fn try_get_cfg<T: DeserializeOwned>() -> Result<T> {
 struct Cli {
     #[command(subcommand)]
     cmd: Option<Cmd>,
+    /// Read from advertisements instead of connecting, needs Smart Home integration enabled
+    #[arg(long, global = true)]
+    passive: bool,
+    /// Output format of readings, history and device info
+    #[arg(long, global = true, value_enum, default_value_t = Output::Text)]
+    output: Output,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Output {
+    Text,
+    /// A single pretty printed document, streaming modes fall back to ndjson
+    Json,
+    /// One compact object per line
+    Ndjson,
+}
+
+/// A reading, history record or device info tagged with the device it came from, what
+/// `--output json|ndjson` prints
+#[derive(Serialize)]
+struct Record<'a, T: Serialize> {
+    device: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<&'a str>,
+    #[serde(flatten)]
+    data: &'a T,
+}
+
+impl<'a, T: Serialize> Record<'a, T> {
+    fn new(labels: &'a metric::DeviceLabels, data: &'a T) -> Self {
+        Self {
+            device: &labels.address,
+            name: Some(labels.name.as_str()).filter(|x| !x.is_empty()),
+            data,
+        }
+    }
+}
+
+/// Prints `value` as a single line for ndjson, or pretty for json
+fn print_json<T: Serialize>(output: Output, value: &T) -> Result<()> {
+    match output {
+        Output::Json => println!("{}", serde_json::to_string_pretty(value)?),
+        _ => println!("{}", serde_json::to_string(value)?),
+    }
+    Ok(())
 }
 
 #[derive(Debug, Clone, Subcommand)]
@@ -42,6 +384,146 @@ enum Cmd {
     Oneline,
     StreamingOneline,
     Service,
+    /// Download the on-device log, only what's new since the last run unless --full
+    History {
+        /// Download everything the device holds
+        #[arg(long)]
+        full: bool,
+    },
+    /// Print model, serial, firmware and battery level
+    Info,
+    /// Read or change device settings
+    Settings {
+        #[command(subcommand)]
+        action: SettingsCmd,
+    },
+    /// Read or change CO2 sensor calibration
+    Calibration {
+        #[command(subcommand)]
+        action: CalibrationCmd,
+    },
+    /// Print readings from the local database
+    Query {
+        /// Address or configured name, every device when left out
+        #[arg(long)]
+        device: Option<String>,
+        /// Start of the range, RFC 3339 or local time as "YYYY-MM-DD[ HH:MM[:SS]]"
+        #[arg(long, value_parser = parse_time)]
+        from: Option<DateTime<Utc>>,
+        /// End of the range, same formats as --from
+        #[arg(long, value_parser = parse_time)]
+        to: Option<DateTime<Utc>>,
+    },
+    /// Write readings from the local database as files other tools read
+    Export(ExportArgs),
+    /// Merge Aranet Home app CSV exports into the local database
+    Import {
+        /// Address or configured name of the device the files were exported from
+        #[arg(long)]
+        device: String,
+        /// Seconds within which an imported reading counts as one already stored, keep it
+        /// below the measurement interval
+        #[arg(long)]
+        tolerance: Option<u32>,
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+    },
+}
+
+#[derive(Debug, Clone, Args)]
+struct ExportArgs {
+    #[arg(long, value_enum, default_value_t = ExportFormat::Csv)]
+    format: ExportFormat,
+    /// Address or configured name, every device when left out
+    #[arg(long)]
+    device: Option<String>,
+    /// Start of the range, same formats as query --from
+    #[arg(long, value_parser = parse_time)]
+    since: Option<DateTime<Utc>>,
+    /// End of the range, same formats as query --from
+    #[arg(long, value_parser = parse_time)]
+    until: Option<DateTime<Utc>>,
+    /// Directory to write a file per device into, stdout when left out
+    #[arg(long)]
+    out: Option<PathBuf>,
+    /// Split parquet into a device=<address>/date=<YYYY-MM-DD> tree under --out
+    #[arg(long, requires = "out")]
+    partition: bool,
+    /// Download what's new in the device's history first, needs --device
+    #[arg(long, requires = "device")]
+    fetch: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum ExportFormat {
+    /// Same layout as the Aranet Home app's export
+    Csv,
+    /// One row per sample with typed columns, for DuckDB, Polars and friends
+    Parquet,
+}
+
+impl ExportFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Parquet => "parquet",
+        }
+    }
+}
+
+fn parse_time(s: &str) -> std::result::Result<DateTime<Utc>, String> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(s) {
+        return Ok(time.to_utc());
+    }
+    let local = ["%Y-%m-%d %H:%M:%S", "%Y-%m-%d %H:%M"]
+        .iter()
+        .find_map(|f| NaiveDateTime::parse_from_str(s, f).ok())
+        .or_else(|| {
+            NaiveDate::parse_from_str(s, "%Y-%m-%d")
+                .ok()
+                .and_then(|x| x.and_hms_opt(0, 0, 0))
+        })
+        .ok_or(format!("Invalid time {s}"))?;
+    local
+        .and_local_timezone(Local)
+        .earliest()
+        .map(|x| x.to_utc())
+        .ok_or(format!("{s} doesn't exist in the local timezone"))
+}
+
+#[derive(Debug, Clone, Subcommand)]
+enum CalibrationCmd {
+    Status,
+    /// Force a 400ppm calibration, the device must be outdoors in fresh air
+    Force {
+        /// Confirm overwriting the current calibration
+        #[arg(long)]
+        yes: bool,
+    },
+    /// Turn automatic calibration on or off
+    Auto {
+        #[arg(value_parser = BoolishValueParser::new())]
+        on: bool,
+    },
+}
+
+#[derive(Debug, Clone, Subcommand)]
+enum SettingsCmd {
+    Get,
+    Set {
+        /// Measurement interval in minutes: 1, 2, 5 or 10
+        #[arg(long)]
+        interval: Option<Interval>,
+        /// Smart Home integration, on or off
+        #[arg(long, value_parser = BoolishValueParser::new())]
+        smart_home: Option<bool>,
+        /// Bluetooth range: standard or extended
+        #[arg(long)]
+        range: Option<BluetoothRange>,
+        /// Temperature unit on the display: c or f
+        #[arg(long)]
+        temp_unit: Option<TempUnit>,
+    },
 }
 
 fn main() {
@@ -52,206 +534,898 @@ fn main() {
         .build()
         .expect("Building runtime failed");
 
-    rt.block_on(async {
-        let cfg = try_get_cfg::<Cfg>().unwrap();
-        let mut addresses: Vec<Address> = cfg
-            .macs
-            .iter()
-            .map(|x| str_mac_to_array(x).unwrap())
-            .map(|x| Address::new(x))
-            .collect();
+    if let Err(e) = rt.block_on(run(cli)) {
+        eprintln!("Error: {e}");
+        if let Some(advice) = advice(&e) {
+            eprintln!("{advice}");
+        }
+        process::exit(1);
+    }
+}
+
+/// What the user can do about an error, if anything
+fn advice(e: &Error) -> Option<&'static str> {
+    match e {
+        Error::AdapterMissing(_) => Some(
+            "Check `adapter` in ~/.config/aranet/config.toml, `bluetoothctl list` shows the available adapters",
+        ),
+        Error::DeviceNotFound(_) => Some(
+            "Make sure the device is powered and in range, and its address is listed in `macs` or `devices`",
+        ),
+        Error::PairingRejected { .. } => Some(
+            "Remove the device with `bluetoothctl remove <address>` and pair again using the PIN shown on the device",
+        ),
+        Error::MissingCharacteristic(_) => {
+            Some("The device firmware may be too old, update it with the Aranet Home app")
+        }
+        Error::ShortPayload { .. } | Error::Malformed { .. } => {
+            Some("The device sent something unexpected, its firmware may not be supported yet")
+        }
+        Error::Timeout(_) => {
+            Some("Move closer to the device or raise `conn_timeout_ms` in the config")
+        }
+        Error::Config(_) => Some("See the README for an example config"),
+        Error::Bluetooth(_) => Some("Check that bluetoothd is running and the adapter is powered"),
+        _ => None,
+    }
+}
 
-        let session = bluer::Session::new().await.unwrap();
+async fn run(cli: Cli) -> Result<()> {
+    let cfg = try_get_cfg::<Cfg>()?;
+    let mut addresses = cfg.addresses()?;
 
-        let _agent = session
-            .register_agent(Agent {
-                request_passkey: Some(Box::new(get_passkey)),
-                ..Default::default()
-            })
-            .await
-            .unwrap();
-
-        let adapter = session.adapter(&cfg.adapter).unwrap();
-        adapter.set_powered(true).await.unwrap();
-
-        let main_adapter = adapter.clone();
-
-        let (dev_sender, mut dev_receiver) = tokio::sync::mpsc::unbounded_channel::<Device>();
-
-        tokio::spawn(async move {
-            if let Ok(mut stream) = adapter.discover_devices().await {
-                eprintln!("Discovering...");
-                while let Some(event) = stream.next().await {
-                    // eprintln!("Event: {event:?}");
-                    match event {
-                        AdapterEvent::DeviceAdded(address) => {
-                            if let Some(idx) = addresses.iter().position(|x| x == &address) {
-                                // Remove found addresses so we don't try them multiple times
-                                addresses.swap_remove(idx);
-
-                                eprintln!("Found: {address:?}");
-                                if let Ok(device) = adapter.device(address) {
-                                    let sender = dev_sender.clone();
-                                    tokio::spawn(async move {
-                                        if !device.is_connected().await? {
-                                            eprintln!("    Connecting: {device:?}");
-                                            device.connect().await?;
-                                            eprintln!("    Connected!: {device:?}");
-                                        }
+    // Answered from the database alone, no bluetooth needed
+    if let Some(Cmd::Query { device, from, to }) = &cli.cmd {
+        let device = device.as_deref().map(|x| cfg.resolve(x)).transpose()?;
+        let store = Store::open(cfg.database()?)?;
+        let fahrenheit = cfg.fahrenheit.unwrap_or(false);
+        for sample in store.query(device.map(|x| x.to_string()).as_deref(), *from, *to)? {
+            println!("{}", sample.oneline(fahrenheit));
+        }
+        return Ok(());
+    }
+
+    if let Some(Cmd::Import {
+        device,
+        tolerance,
+        files,
+    }) = &cli.cmd
+    {
+        let device = cfg.resolve(device)?.to_string();
+        let mut store = Store::open(cfg.database()?)?;
+        if let Some(secs) = tolerance {
+            store.set_tolerance(TimeDelta::seconds(*secs as i64));
+        }
+        for file in files {
+            eprintln!("Importing {}", file.display());
+            let samples = aranet_home::read_csv(fs::File::open(file)?, &device, &Local)?;
+            let stored = store.insert_all(&samples)?;
+            eprintln!("Stored {stored} new of {} readings", samples.len());
+        }
+        return Ok(());
+    }
+
+    if let Some(Cmd::Calibration {
+        action: CalibrationCmd::Force { yes: false },
+    }) = &cli.cmd
+    {
+        return Err(Error::Unsupported(
+            "Forced calibration overwrites the current calibration, \
+             place the device outdoors and rerun with --yes"
+                .to_string(),
+        ));
+    }
+
+    if let Some(Cmd::Export(args)) = &cli.cmd {
+        if args.partition && args.format != ExportFormat::Parquet {
+            return Err(Error::Unsupported(
+                "--partition is only supported for parquet".to_string(),
+            ));
+        }
+        let device = args.device.as_deref().map(|x| cfg.resolve(x)).transpose()?;
+        if !args.fetch {
+            let store = Store::open(cfg.database()?)?;
+            return export(&cfg, &store, device, args);
+        }
+        // Only connect to the device being exported, clap makes sure there is one
+        addresses = device.into_iter().collect();
+    }
+
+    // Everything but current readings needs a connection
+    if cli.passive
+        && !matches!(
+            cli.cmd,
+            None | Some(Cmd::Oneline | Cmd::StreamingOneline | Cmd::Service)
+        )
+    {
+        return Err(Error::Unsupported(
+            "This can't be read from advertisements, drop --passive".to_string(),
+        ));
+    }
+
+    if cli.output != Output::Text
+        && !matches!(
+            cli.cmd,
+            None | Some(
+                Cmd::Oneline
+                    | Cmd::StreamingOneline
+                    | Cmd::Service
+                    | Cmd::History { .. }
+                    | Cmd::Info
+            )
+        )
+    {
+        return Err(Error::Unsupported(
+            "--output is only supported for readings, history and info".to_string(),
+        ));
+    }
+
+    let session = bluer::Session::new().await?;
+
+    // Nothing gets paired in passive mode, so there's no need for pin entry
+    let _agent = if cli.passive {
+        None
+    } else {
+        let passkey = env::var("ARANET_PASSKEY")
+            .ok()
+            .or(cfg.passkey.clone())
+            .map(|x| parse_passkey(&x))
+            .transpose()
+            .map_err(|e| Error::Config(format!("passkey or ARANET_PASSKEY: {e}")))?;
+        Some(
+            session
+                .register_agent(Agent {
+                    request_passkey: Some(get_passkey(
+                        cfg.pinentry.clone().unwrap_or("pinentry-qt".to_string()),
+                        passkey,
+                    )),
+                    ..Default::default()
+                })
+                .await?,
+        )
+    };
+
+    if !session.adapter_names().await?.contains(&cfg.adapter) {
+        return Err(Error::AdapterMissing(cfg.adapter.clone()));
+    }
+    let adapter = session.adapter(&cfg.adapter)?;
+    adapter.set_powered(true).await?;
+
+    let main_adapter = adapter.clone();
 
-                                        eprintln!("    Scanning: {device:?}");
-
-                                        let mut count: u32 = 0;
-                                        loop {
-                                            let x = device.rssi().await?;
-                                            eprintln!("    RSSI: {x:?} on {device:?}");
-                                            match x {
-                                                Some(_) => {
-                                                    sender.send(device).unwrap();
-                                                    break;
-                                                }
-                                                _ => {
-                                                    count += 1;
-                                                    tokio::time::sleep(Duration::from_millis(200))
-                                                        .await
-                                                }
-                                            }
-
-                                            // Just so we don't busy loop forever on connections which aren't present
-                                            // this probably can't happen but i'm not 100% sure.
-                                            if count > 100 {
-                                                break;
-                                            }
+    let (dev_sender, mut dev_receiver) = tokio::sync::mpsc::unbounded_channel::<Device>();
+    let (ad_sender, mut ad_receiver) =
+        tokio::sync::mpsc::unbounded_channel::<(Address, Result<Reading>)>();
+    let passive = cli.passive;
+    // Supervisors make the first connection themselves, with backoff and rediscovery
+    let supervised = matches!(cli.cmd, Some(Cmd::StreamingOneline | Cmd::Service));
+
+    tokio::spawn(async move {
+        if let Ok(mut stream) = adapter.discover_devices().await {
+            eprintln!("Discovering...");
+            while let Some(event) = stream.next().await {
+                // eprintln!("Event: {event:?}");
+                if let AdapterEvent::DeviceAdded(address) = event {
+                    if let Some(idx) = addresses.iter().position(|x| x == &address) {
+                        // Remove found addresses so we don't try them multiple times
+                        addresses.swap_remove(idx);
+
+                        eprintln!("Found: {address:?}");
+                        if passive {
+                            if let Ok(device) = adapter.device(address) {
+                                tokio::spawn(watch_advertisements(device, ad_sender.clone()));
+                            }
+                        } else if let Ok(device) = adapter.device(address) {
+                            if supervised {
+                                let _ = dev_sender.send(device);
+                                continue;
+                            }
+                            let sender = dev_sender.clone();
+                            tokio::spawn(async move {
+                                if !device.is_connected().await? {
+                                    eprintln!("    Connecting: {device:?}");
+                                    device.connect().await?;
+                                    eprintln!("    Connected!: {device:?}");
+                                }
+
+                                eprintln!("    Scanning: {device:?}");
+
+                                let mut count: u32 = 0;
+                                loop {
+                                    let x = device.rssi().await?;
+                                    eprintln!("    RSSI: {x:?} on {device:?}");
+                                    match x {
+                                        Some(_) => {
+                                            let _ = sender.send(device);
+                                            break;
                                         }
+                                        _ => {
+                                            count += 1;
+                                            tokio::time::sleep(Duration::from_millis(200)).await
+                                        }
+                                    }
 
-                                        Ok::<(), bluer::Error>(())
-                                    });
+                                    // Just so we don't busy loop forever on connections which aren't present
+                                    // this probably can't happen but i'm not 100% sure.
+                                    if count > 100 {
+                                        break;
+                                    }
                                 }
-                            }
+
+                                Ok::<(), bluer::Error>(())
+                            });
                         }
-                        _ => {}
                     }
                 }
             }
-        });
+        }
+    });
 
-        // dev should already be connected from the task
-        let dev = timeout(
-            Duration::from_millis(cfg.conn_timeout_ms.unwrap_or(15000)),
-            dev_receiver.recv(),
-        )
-        .await
-        .expect("Timeout while searching for device")
-        .expect("Failed to find device within range");
+    let conn_timeout = Duration::from_millis(cfg.conn_timeout_ms.unwrap_or(15000));
+    let stream_freq = Duration::from_secs(cfg.stream_freq.unwrap_or(30));
+
+    let gauges = match cli.cmd {
+        Some(Cmd::Service) => {
+            let address = cfg
+                .prometheus_address
+                .as_deref()
+                .unwrap_or("127.0.0.1:8080");
+            let address = address
+                .to_socket_addrs()
+                .ok()
+                .and_then(|mut x| x.next())
+                .ok_or(Error::Config(format!(
+                    "Invalid prometheus_address {address:?}"
+                )))?;
+            metric::start_prometheus_listener_task(address).await?;
 
-        eprintln!("Dev: {dev:?}");
+            Some(metric::Gauges::register()?)
+        }
+        _ => None,
+    };
+
+    let mut sinks: Vec<Box<dyn Sink>> = Vec::new();
+    if let Some(Cmd::Service) = cli.cmd {
+        sinks.push(Box::new(Store::open(cfg.database()?)?));
+        if let Some(influx) = &cfg.influx {
+            sinks.push(Box::new(influx.connect(&cfg)?));
+        }
+        if let Some(mqtt) = &cfg.mqtt {
+            sinks.push(Box::new(mqtt.connect(&cfg)?));
+        }
+        if let Some(remote) = &cfg.remote_write {
+            sinks.push(Box::new(remote.connect(&cfg)?));
+        }
+    }
+
+    let reporter = Reporter {
+        gauges,
+        sinks: Arc::new(Mutex::new(sinks)),
+        fahrenheit: cfg.fahrenheit.unwrap_or(false),
+        output: cli.output,
+        // Only tag output lines with the device when there's something to tell apart
+        tagged: cfg.addresses()?.len() > 1,
+    };
+    let fahrenheit = reporter.fahrenheit;
 
-        match dev.is_paired().await {
-            Ok(is_paired) => {
-                if !is_paired {
-                    println!("Device is not paired. Attempting to pair...");
+    if passive {
+        let (address, readings) = timeout(conn_timeout, ad_receiver.recv())
+            .await
+            .map_err(|_| Error::Timeout("waiting for an advertisement"))?
+            .ok_or(Error::DeviceNotFound(cfg.describe_devices()))?;
 
-                    match dev.pair().await {
-                        Ok(_) => println!("Pairing successful!"),
-                        Err(err) => eprintln!("Pairing failed: {:?}", err),
+        match cli.cmd {
+            Some(Cmd::Oneline) | None if cli.output != Output::Text => print_json(
+                cli.output,
+                &Record::new(&cfg.labels(address), &readings?.timed(Utc::now())),
+            )?,
+            Some(Cmd::Oneline) => readings?.print_oneline(fahrenheit),
+            None => println!("{}", readings?),
+            _ => {
+                let mut next = Some((address, readings));
+                while let Some((address, readings)) = next {
+                    match readings {
+                        Ok(readings) => reporter.report(&cfg.labels(address), &readings),
+                        Err(e) => eprintln!("Not watching {address}: {e}"),
+                    }
+                    next = ad_receiver.recv().await;
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    match cli.cmd {
+        Some(Cmd::StreamingOneline | Cmd::Service) => {
+            let mut pollers = JoinSet::new();
+            let search = tokio::time::sleep(conn_timeout);
+            tokio::pin!(search);
+
+            loop {
+                tokio::select! {
+                    Some(dev) = dev_receiver.recv() => {
+                        eprintln!("Dev: {dev:?}");
+                        let labels = cfg.labels(dev.address());
+                        pollers.spawn(supervise_device(
+                            main_adapter.clone(),
+                            dev,
+                            labels,
+                            reporter.clone(),
+                            stream_freq,
+                            conn_timeout,
+                        ));
+                    }
+                    // Supervisors only ever stop by panicking
+                    Some(Err(e)) = pollers.join_next() => {
+                        eprintln!("Polling task failed: {e:?}");
+                        if pollers.is_empty() {
+                            return Err(Error::DeviceNotFound("lost every device".to_string()));
+                        }
+                    }
+                    _ = &mut search, if pollers.is_empty() => {
+                        return Err(Error::Timeout("searching for devices"));
                     }
                 }
             }
-            Err(e) => {
-                println!("Device Err: {e:?}");
-                println!(
+        }
+        cmd => {
+            // dev should already be connected from the task
+            let dev = timeout(conn_timeout, dev_receiver.recv())
+                .await
+                .map_err(|_| Error::Timeout("searching for device"))?
+                .ok_or(Error::DeviceNotFound(cfg.describe_devices()))?;
+
+            eprintln!("Dev: {dev:?}");
+
+            if let Err(e) = dev.is_paired().await {
+                eprintln!("Device Err: {e:?}");
+                eprintln!(
                     "Available device addresses: {:#?}",
                     main_adapter.device_addresses().await
                 );
-                return;
+                return Err(Error::DeviceNotFound(dev.address().to_string()));
             }
-        }
 
-        let endpoint = map_device_endpoints(&dev).await.unwrap();
+            let endpoint = prepare_device(&dev).await?;
+            let labels = cfg.labels(dev.address());
 
-        if let Some(cmd) = cli.cmd {
             match cmd {
-                Cmd::Oneline => {
-                    let readings = endpoint.read().await.unwrap();
-                    readings.print_oneline(cfg.fahrenheit.unwrap_or(false));
+                Some(Cmd::Oneline) | None if cli.output != Output::Text => {
+                    let readings = endpoint.read().await?;
+                    print_json(
+                        cli.output,
+                        &Record::new(&labels, &readings.timed(Utc::now())),
+                    )?;
+                }
+                Some(Cmd::Oneline) => {
+                    let readings = endpoint.read().await?;
+                    readings.print_oneline(fahrenheit);
                 }
-                Cmd::StreamingOneline => loop {
-                    if !dev.is_connected().await.unwrap() {
-                        dev.connect().await.unwrap();
+                Some(Cmd::Settings {
+                    action: SettingsCmd::Get,
+                }) => {
+                    let settings = endpoint.read_settings().await?;
+                    println!("{}", settings);
+                }
+                Some(Cmd::Settings {
+                    action:
+                        SettingsCmd::Set {
+                            interval,
+                            smart_home,
+                            range,
+                            temp_unit,
+                        },
+                }) => {
+                    let cmds = [
+                        interval.map(Command::SetInterval),
+                        smart_home.map(Command::SetSmartHome),
+                        range.map(Command::SetRange),
+                        temp_unit.map(Command::SetTempUnit),
+                    ];
+                    if cmds.iter().all(Option::is_none) {
+                        eprintln!("Nothing to set");
+                        return Ok(());
                     }
-
-                    let readings = endpoint.read().await.unwrap();
-                    readings.print_oneline(cfg.fahrenheit.unwrap_or(false));
-                    tokio::time::sleep(Duration::from_secs(cfg.stream_freq.unwrap_or(30))).await;
-                },
-                Cmd::Service => {
-                    let address = cfg
-                        .prometheus_address
-                        .unwrap_or("127.0.0.1:8080".to_string())
-                        .to_socket_addrs()
-                        .unwrap()
-                        .next()
-                        .unwrap();
-                    metric::start_prometheus_listener_task(address)
-                        .await
-                        .unwrap();
-
-                    let metric_co2 = IntGauge::new("aranet_co2", "Co2 in ppm").unwrap();
-                    register(Box::new(metric_co2.clone()))
-                        .map(|()| metric_co2.clone())
-                        .unwrap();
-
-                    let metric_temp_f =
-                        Gauge::new("aranet_temp_fahrenheit", "Temp in Fahrenheit").unwrap();
-                    register(Box::new(metric_temp_f.clone()))
-                        .map(|()| metric_temp_f.clone())
-                        .unwrap();
-
-                    let metric_temp_c =
-                        Gauge::new("aranet_temp_celsius", "Temp in Celsius").unwrap();
-                    register(Box::new(metric_temp_c.clone()))
-                        .map(|()| metric_temp_c.clone())
-                        .unwrap();
-
-                    let metric_relative_humidity =
-                        IntGauge::new("aranet_relative_humidity", "Relative humidity %").unwrap();
-                    register(Box::new(metric_relative_humidity.clone()))
-                        .map(|()| metric_relative_humidity.clone())
-                        .unwrap();
-
-                    let metric_preasure =
-                        Gauge::new("aranet_preasure", "Air preasure in hPa").unwrap();
-                    register(Box::new(metric_preasure.clone()))
-                        .map(|()| metric_preasure.clone())
-                        .unwrap();
-
-                    let metric_bat = IntGauge::new("aranet_bat", "Aranet4 battery %").unwrap();
-                    register(Box::new(metric_bat.clone()))
-                        .map(|()| metric_bat.clone())
-                        .unwrap();
-
-                    loop {
-                        if !dev.is_connected().await.unwrap() {
-                            dev.connect().await.unwrap();
+                    for cmd in cmds.into_iter().flatten() {
+                        endpoint.apply(cmd).await?;
+                        println!("Applied {cmd:?}");
+                    }
+                    println!("{}", endpoint.read_settings().await?);
+                }
+                Some(Cmd::Calibration { action }) => {
+                    let calibration = match action {
+                        CalibrationCmd::Status => endpoint.read_calibration().await?,
+                        // Refused without --yes before connecting
+                        CalibrationCmd::Force { .. } => endpoint.calibrate().await?,
+                        CalibrationCmd::Auto { on } => endpoint.set_auto_calibration(on).await?,
+                    };
+                    println!("{}", calibration);
+                }
+                Some(Cmd::Info) => {
+                    let info = endpoint.read_info().await?;
+                    match cli.output {
+                        Output::Text => println!("{}", info),
+                        output => print_json(output, &Record::new(&labels, &info))?,
+                    }
+                }
+                Some(Cmd::History { full }) => {
+                    let mut store = Store::open(cfg.database()?)?;
+                    let records = sync_history(&endpoint, &mut store, dev.address(), full).await?;
+                    match cli.output {
+                        Output::Text => records.iter().for_each(|x| x.print_oneline(fahrenheit)),
+                        Output::Json => print_json(
+                            Output::Json,
+                            &records
+                                .iter()
+                                .map(|x| Record::new(&labels, x))
+                                .collect::<Vec<_>>(),
+                        )?,
+                        Output::Ndjson => {
+                            for record in &records {
+                                print_json(Output::Ndjson, &Record::new(&labels, record))?;
+                            }
                         }
-                        let readings = endpoint.read().await.unwrap();
-                        readings.print_oneline(cfg.fahrenheit.unwrap_or(false));
-
-                        metric_co2.set(readings.c02 as i64);
-                        metric_temp_f.set(readings.temp.f_float());
-                        metric_temp_c.set(readings.temp.c_float());
-                        metric_relative_humidity.set(readings.humidity as i64);
-                        metric_preasure.set(readings.preasure as f64 / 10.0);
-                        metric_bat.set(readings.bat as i64);
-
-                        tokio::time::sleep(Duration::from_secs(cfg.stream_freq.unwrap_or(30)))
-                            .await;
                     }
                 }
-            };
+                Some(Cmd::Export(args)) => {
+                    let mut store = Store::open(cfg.database()?)?;
+                    sync_history(&endpoint, &mut store, dev.address(), false).await?;
+                    export(&cfg, &store, Some(dev.address()), &args)?;
+                }
+                _ => {
+                    let readings = endpoint.read().await?;
+                    println!("{}", readings);
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Downloads the device's history since the last sync, or all of it when `full`, into `store`
+async fn sync_history(
+    endpoint: &EndPoints,
+    store: &mut Store,
+    address: Address,
+    full: bool,
+) -> Result<Vec<HistoryRecord>> {
+    let device = address.to_string();
+    let last = if full {
+        None
+    } else {
+        store.sync_state(&device)?
+    };
+    let (records, state) = endpoint.read_history_since(last.as_ref()).await?;
+    let samples: Vec<Sample> = records
+        .iter()
+        .map(|x| Sample::from_history(&device, x))
+        .collect();
+    let stored = store.insert_all(&samples)?;
+    store.set_sync_state(&device, &state)?;
+    eprintln!("Stored {stored} new of {} records", samples.len());
+    Ok(records)
+}
+
+/// Writes stored samples to a file per device in `out`, or to stdout when they're all of a
+/// single device
+fn export(cfg: &Cfg, store: &Store, device: Option<Address>, args: &ExportArgs) -> Result<()> {
+    let samples = store.query(
+        device.map(|x| x.to_string()).as_deref(),
+        args.since,
+        args.until,
+    )?;
+    let fahrenheit = cfg.fahrenheit.unwrap_or(false);
+    let format = args.format;
+    let write = |w: &mut (dyn io::Write + Send), samples: &[Sample]| match format {
+        ExportFormat::Csv => aranet_home::write_csv(w, samples, &Local, fahrenheit),
+        ExportFormat::Parquet => columnar::write_parquet(w, samples),
+    };
+    // Query orders by device
+    let mut devices = samples.chunk_by(|a, b| a.device == b.device).peekable();
+
+    if let (true, Some(dir)) = (args.partition, &args.out) {
+        let files = columnar::write_partitioned(dir, &samples)?;
+        eprintln!(
+            "Wrote {} readings to {} files under {}",
+            samples.len(),
+            files.len(),
+            dir.display()
+        );
+        return Ok(());
+    }
+
+    let Some(dir) = &args.out else {
+        let Some(samples) = devices.next() else {
+            eprintln!("Nothing to export");
+            return Ok(());
+        };
+        if devices.peek().is_some() {
+            return Err(Error::Unsupported(
+                "Readings of several devices, pick one with --device or write a file per device with --out"
+                    .to_string(),
+            ));
+        }
+        return write(&mut io::stdout(), samples);
+    };
+
+    fs::create_dir_all(dir)?;
+    for samples in devices {
+        let address = &samples[0].device;
+        // Files are named after the configured name when there is one
+        let name = address
+            .parse()
+            .ok()
+            .map(|x| cfg.labels(x).name)
+            .filter(|x| !x.is_empty())
+            .unwrap_or(address.replace(':', ""));
+        let path = dir.join(format!("{name}.{}", format.extension()));
+        write(&mut fs::File::create(&path)?, samples)?;
+        eprintln!("Wrote {} readings to {}", samples.len(), path.display());
+    }
+    Ok(())
+}
+
+/// Pairs with the device if needed and maps it's characteristics
+async fn prepare_device(dev: &Device) -> Result<EndPoints> {
+    if !dev.is_paired().await? {
+        println!("Device is not paired. Attempting to pair...");
+
+        dev.pair().await.map_err(|e| Error::PairingRejected {
+            address: dev.address().to_string(),
+            reason: e.message,
+        })?;
+        println!("Pairing successful!");
+    }
+
+    map_device_endpoints(dev).await
+}
+
+/// Where readings end up in the streaming modes
+#[derive(Clone)]
+struct Reporter {
+    gauges: Option<metric::Gauges>,
+    /// Only filled in service mode
+    sinks: Arc<Mutex<Vec<Box<dyn Sink>>>>,
+    fahrenheit: bool,
+    /// Json is printed as ndjson, a stream never ends so it can't be a single document
+    output: Output,
+    tagged: bool,
+}
+
+impl Reporter {
+    fn tag(labels: &metric::DeviceLabels) -> &str {
+        if labels.name.is_empty() {
+            &labels.address
         } else {
-            let readings = endpoint.read().await.unwrap();
-            println!("{}", readings);
+            &labels.name
         }
-    });
+    }
+
+    fn report(&self, labels: &metric::DeviceLabels, readings: &Reading) {
+        if self.output != Output::Text {
+            if let Err(e) = print_json(
+                Output::Ndjson,
+                &Record::new(labels, &readings.timed(Utc::now())),
+            ) {
+                eprintln!("Printing reading failed: {e}");
+            }
+        } else if self.tagged {
+            println!(
+                "{} {}",
+                Self::tag(labels),
+                readings.oneline(self.fahrenheit)
+            );
+        } else {
+            readings.print_oneline(self.fahrenheit);
+        }
+
+        if let Some(gauges) = &self.gauges {
+            gauges.set(labels, readings);
+        }
+        self.write(&[Sample::from_reading(&labels.address, readings)]);
+    }
+
+    /// Returns the most samples any sink took
+    /// Returns what each sink that took the samples reported, new rows for the store
+    fn write(&self, samples: &[Sample]) -> Vec<(&'static str, usize)> {
+        let Ok(mut sinks) = self.sinks.lock() else {
+            return Vec::new();
+        };
+        let mut written = Vec::new();
+        for (res, sink) in sink::write_all(&mut sinks, samples)
+            .into_iter()
+            .zip(sinks.iter())
+        {
+            match res {
+                Ok(x) => written.push((sink.name(), x)),
+                Err(e) => eprintln!("Writing to {} failed: {e}", sink.name()),
+            }
+        }
+        written
+    }
+
+    fn latest(&self, device: &str) -> Option<DateTime<Utc>> {
+        self.sinks
+            .lock()
+            .ok()
+            .and_then(|x| sink::latest(&x, device))
+    }
+
+    /// Fetches what the device measured since `last` from it's history and writes it to the
+    /// sinks, Prometheus has no way to take it so the gauges only ever show current values
+    async fn backfill(
+        &self,
+        labels: &metric::DeviceLabels,
+        endpoint: &EndPoints,
+        last: DateTime<Utc>,
+    ) {
+        let tag = Self::tag(labels);
+        if self.sinks.lock().map_or(true, |x| x.is_empty()) {
+            return;
+        }
+        if !matches!(endpoint.model().await, Ok(DeviceModel::Aranet4)) {
+            return;
+        }
+        match endpoint.read_missed(last).await {
+            Ok(records) if records.is_empty() => {}
+            Ok(records) => {
+                let samples: Vec<Sample> = records
+                    .iter()
+                    .map(|x| Sample::from_history(&labels.address, x))
+                    .collect();
+                let written = self
+                    .write(&samples)
+                    .iter()
+                    .map(|(name, n)| format!("{name}: {n}"))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                eprintln!(
+                    "{tag}: recovered {} points missed since {} ({written})",
+                    samples.len(),
+                    last.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S")
+                );
+            }
+            Err(e) => eprintln!("{tag}: backfill failed: {e}"),
+        }
+    }
+
+    fn set_stale(&self, labels: &metric::DeviceLabels, stale: bool) {
+        if let Some(gauges) = &self.gauges {
+            gauges.set_stale(labels, stale);
+        }
+        if let Ok(mut sinks) = self.sinks.lock() {
+            for sink in sinks.iter_mut() {
+                if let Err(e) = sink.set_stale(&labels.address, stale) {
+                    eprintln!("Updating {} failed: {e}", sink.name());
+                }
+            }
+        }
+    }
+
+    /// Only new measurements are reported, duplicates are counted and logged
+    fn poll(
+        &self,
+        labels: &metric::DeviceLabels,
+        readings: &Reading,
+        new: bool,
+        stats: &PollStats,
+    ) {
+        if new {
+            self.report(labels, readings);
+        } else {
+            eprintln!("{}: no new measurement yet ({stats})", Self::tag(labels));
+        }
+        if let Some(gauges) = &self.gauges {
+            gauges.count_poll(labels, new);
+        }
+    }
+}
+
+/// Connection state of a single device in the streaming modes
+enum Link {
+    /// BlueZ forgot about the device, usually after it was out of range for a while
+    Missing,
+    Disconnected(Device),
+    /// Characteristics are mapped again on every connect, handles from before a disconnect
+    /// can't be trusted
+    Connected(Device, Box<EndPoints>),
+}
+
+/// Keeps a single device polled for as long as the process runs, reading just after each
+/// measurement or every `freq` when the device doesn't say when it measures. Failures drop back
+/// to reconnecting, or rediscovering, with backoff while the gauges keep the last known values
+/// marked stale.
+async fn supervise_device(
+    adapter: Adapter,
+    dev: Device,
+    labels: metric::DeviceLabels,
+    reporter: Reporter,
+    freq: Duration,
+    conn_timeout: Duration,
+) {
+    let address = dev.address();
+    let tag = Reporter::tag(&labels).to_string();
+    let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(5 * 60));
+    let mut stats = PollStats::default();
+    // Where the sinks left off before a restart
+    let stored = reporter.latest(&labels.address);
+    let mut link = Link::Disconnected(dev);
+
+    loop {
+        link = match link {
+            Link::Missing => match rediscover(&adapter, address, conn_timeout).await {
+                Ok(dev) => Link::Disconnected(dev),
+                Err(e) => {
+                    retry_after(&tag, &e, &mut backoff).await;
+                    Link::Missing
+                }
+            },
+            Link::Disconnected(dev) => match connect(&adapter, &dev, conn_timeout).await {
+                Ok(Some(endpoint)) => {
+                    eprintln!("{tag}: connected");
+                    if let Some(gauges) = &reporter.gauges {
+                        match endpoint.read_info().await {
+                            Ok(info) => gauges.set_info(&labels, &info),
+                            Err(e) => eprintln!("Reading device info failed: {e}"),
+                        }
+                    }
+                    if let Some(last) = stats.last().or(stored) {
+                        reporter.backfill(&labels, &endpoint, last).await;
+                    }
+                    Link::Connected(dev, Box::new(endpoint))
+                }
+                Ok(None) => {
+                    eprintln!("{tag}: gone from BlueZ, rediscovering");
+                    Link::Missing
+                }
+                Err(e) => {
+                    retry_after(&tag, &e, &mut backoff).await;
+                    Link::Disconnected(dev)
+                }
+            },
+            Link::Connected(dev, endpoint) => {
+                match poll(&endpoint, &labels, &reporter, &mut stats, freq).await {
+                    Ok(delay) => {
+                        // Only a reading counts as recovered, a device which connects fine
+                        // but can't be read would otherwise reconnect in a tight loop
+                        backoff.reset();
+                        reporter.set_stale(&labels, false);
+                        tokio::time::sleep(delay).await;
+                        Link::Connected(dev, endpoint)
+                    }
+                    Err(e) => {
+                        reporter.set_stale(&labels, true);
+                        retry_after(&tag, &e, &mut backoff).await;
+                        Link::Disconnected(dev)
+                    }
+                }
+            }
+        };
+    }
+}
+
+async fn retry_after(tag: &str, e: &Error, backoff: &mut Backoff) {
+    let delay = backoff.next_delay();
+    eprintln!(
+        "{tag}: {e}, retrying in {}s (attempt {})",
+        delay.as_secs(),
+        backoff.attempts()
+    );
+    tokio::time::sleep(delay).await;
+}
+
+/// Scans until BlueZ sees the device again
+async fn rediscover(adapter: &Adapter, address: Address, limit: Duration) -> Result<Device> {
+    let mut events = adapter.discover_devices().await?;
+    if adapter.device_addresses().await?.contains(&address) {
+        return Ok(adapter.device(address)?);
+    }
+    timeout(limit, async {
+        while let Some(event) = events.next().await {
+            if matches!(event, AdapterEvent::DeviceAdded(x) if x == address) {
+                return Ok(adapter.device(address)?);
+            }
+        }
+        Err(Error::DeviceNotFound(address.to_string()))
+    })
+    .await
+    .map_err(|_| Error::Timeout("rediscovering device"))?
+}
+
+/// `None` when BlueZ no longer knows the device
+async fn connect(
+    adapter: &Adapter,
+    dev: &Device,
+    conn_timeout: Duration,
+) -> Result<Option<EndPoints>> {
+    if !adapter.device_addresses().await?.contains(&dev.address()) {
+        return Ok(None);
+    }
+    if !dev.is_connected().await? {
+        timeout(conn_timeout, dev.connect())
+            .await
+            .map_err(|_| Error::Timeout("connecting"))??;
+    }
+    prepare_device(dev).await.map(Some)
+}
+
+/// Takes and reports a single reading, returns how long to wait for the next one
+async fn poll(
+    endpoint: &EndPoints,
+    labels: &metric::DeviceLabels,
+    reporter: &Reporter,
+    stats: &mut PollStats,
+    freq: Duration,
+) -> Result<Duration> {
+    let readings = endpoint.read().await?;
+    let measured = match readings.measured() {
+        Some(measured) => Some(measured),
+        None => endpoint.read_measured().await.ok(),
+    };
+    let new = stats.record(measured);
+    reporter.poll(labels, &readings, new, stats);
+
+    Ok(measured
+        .and_then(|x| schedule::next_poll(x, Utc::now()))
+        .unwrap_or(freq))
+}
+
+/// Forwards readings from the device's advertisements, never connects or pairs. Stops once
+/// the device turns out to be a model whose advertisements can't be decoded. A measurement is
+/// advertised over and over with only it's age changing, so only the first advertisement of
+/// each counter value is forwarded.
+async fn watch_advertisements(
+    device: Device,
+    sender: UnboundedSender<(Address, Result<Reading>)>,
+) -> bluer::Result<()> {
+    let address = device.address();
+    let mut warned = false;
+    let mut last_counter = None;
+    // false once there's no point in watching any longer
+    let mut forward = |data: Option<HashMap<u16, Vec<u8>>>| {
+        let Some(bytes) = data.as_ref().and_then(|x| x.get(&MANUFACTURER_ID)) else {
+            return true;
+        };
+        match Advertisement::parse(bytes) {
+            Ok(Advertisement {
+                readings: Some(readings),
+                counter,
+                ..
+            }) => {
+                if counter != last_counter {
+                    last_counter = counter;
+                    let _ = sender.send((address, Ok(Reading::Aranet4(readings))));
+                }
+            }
+            Err(e @ Error::Unsupported(_)) => {
+                let _ = sender.send((address, Err(e)));
+                return false;
+            }
+            Ok(_) if !warned => {
+                warned = true;
+                eprintln!(
+                    "Smart Home integration is disabled on {address}, no readings advertised"
+                );
+            }
+            Ok(_) => {}
+            Err(e) => eprintln!("Bad advertisement from {address}: {e:?}"),
+        }
+        true
+    };
+
+    let mut events = device.events().await?;
+    if !forward(device.manufacturer_data().await?) {
+        return Ok(());
+    }
+
+    while let Some(event) = events.next().await {
+        if let DeviceEvent::PropertyChanged(DeviceProperty::ManufacturerData(data)) = event {
+            if !forward(Some(data)) {
+                break;
+            }
+        }
+    }
+
+    Ok(())
 }
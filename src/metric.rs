@@ -1,4 +1,4 @@
-use anyhow::Result;
+use chrono::Utc;
 use std::convert::Infallible;
 use std::net::SocketAddr;
 
@@ -7,7 +7,220 @@ use hyper::{body, header, server, service, Request, Response};
 use hyper_util::rt::TokioIo;
 use tokio::net::TcpListener;
 
-use prometheus::{Encoder, TextEncoder};
+use prometheus::{register, Encoder, GaugeVec, IntCounterVec, IntGaugeVec, Opts, TextEncoder};
+
+use crate::{
+    types::{DeviceInfo, Reading},
+    Result,
+};
+
+const LABELS: &[&str] = &["address", "name", "room"];
+const POLL_LABELS: &[&str] = &["address", "name", "room", "result"];
+const INFO_LABELS: &[&str] = &[
+    "address", "name", "room", "model", "firmware", "hardware", "serial",
+];
+
+/// Identifies a device in exported metrics, `name` and `room` are empty when not configured
+#[derive(Debug, Clone)]
+pub struct DeviceLabels {
+    pub address: String,
+    pub name: String,
+    pub room: String,
+}
+
+impl DeviceLabels {
+    fn values(&self) -> [&str; 3] {
+        [&self.address, &self.name, &self.room]
+    }
+}
+
+/// Per device gauges exported in service mode
+#[derive(Clone)]
+pub struct Gauges {
+    co2: IntGaugeVec,
+    temp_f: GaugeVec,
+    temp_c: GaugeVec,
+    relative_humidity: GaugeVec,
+    preasure: GaugeVec,
+    bat: IntGaugeVec,
+    dose_rate: GaugeVec,
+    dose: GaugeVec,
+    radon: IntGaugeVec,
+    last_update: GaugeVec,
+    stale: IntGaugeVec,
+    polls: IntCounterVec,
+    info: IntGaugeVec,
+}
+
+impl Gauges {
+    pub fn register() -> Result<Self> {
+        let gauges = Self {
+            co2: IntGaugeVec::new(Opts::new("aranet_co2", "Co2 in ppm"), LABELS)?,
+            temp_f: GaugeVec::new(
+                Opts::new("aranet_temp_fahrenheit", "Temp in Fahrenheit"),
+                LABELS,
+            )?,
+            temp_c: GaugeVec::new(Opts::new("aranet_temp_celsius", "Temp in Celsius"), LABELS)?,
+            relative_humidity: GaugeVec::new(
+                Opts::new("aranet_relative_humidity", "Relative humidity %"),
+                LABELS,
+            )?,
+            preasure: GaugeVec::new(Opts::new("aranet_preasure", "Air preasure in hPa"), LABELS)?,
+            bat: IntGaugeVec::new(Opts::new("aranet_bat", "Battery %"), LABELS)?,
+            dose_rate: GaugeVec::new(
+                Opts::new("aranet_radiation_dose_rate", "Radiation dose rate in µSv/h"),
+                LABELS,
+            )?,
+            dose: GaugeVec::new(
+                Opts::new("aranet_radiation_dose", "Accumulated radiation dose in mSv"),
+                LABELS,
+            )?,
+            radon: IntGaugeVec::new(
+                Opts::new("aranet_radon", "Radon concentration in Bq/m³"),
+                LABELS,
+            )?,
+            last_update: GaugeVec::new(
+                Opts::new(
+                    "aranet_last_update_timestamp_seconds",
+                    "Unix time the last reading was measured, or read when the device doesn't say",
+                ),
+                LABELS,
+            )?,
+            stale: IntGaugeVec::new(
+                Opts::new(
+                    "aranet_stale",
+                    "1 while the device is unreachable and the other gauges hold its last known values",
+                ),
+                LABELS,
+            )?,
+            polls: IntCounterVec::new(
+                Opts::new(
+                    "aranet_polls_total",
+                    "Polls by result, new or duplicate of the previous measurement",
+                ),
+                POLL_LABELS,
+            )?,
+            info: IntGaugeVec::new(
+                Opts::new(
+                    "aranet_device_info",
+                    "Device model, firmware and serial, always 1",
+                ),
+                INFO_LABELS,
+            )?,
+        };
+
+        register(Box::new(gauges.co2.clone()))?;
+        register(Box::new(gauges.temp_f.clone()))?;
+        register(Box::new(gauges.temp_c.clone()))?;
+        register(Box::new(gauges.relative_humidity.clone()))?;
+        register(Box::new(gauges.preasure.clone()))?;
+        register(Box::new(gauges.bat.clone()))?;
+        register(Box::new(gauges.dose_rate.clone()))?;
+        register(Box::new(gauges.dose.clone()))?;
+        register(Box::new(gauges.radon.clone()))?;
+        register(Box::new(gauges.last_update.clone()))?;
+        register(Box::new(gauges.stale.clone()))?;
+        register(Box::new(gauges.polls.clone()))?;
+        register(Box::new(gauges.info.clone()))?;
+
+        Ok(gauges)
+    }
+
+    /// Only touches the gauges the reading's model actually measures
+    pub fn set(&self, device: &DeviceLabels, readings: &Reading) {
+        let labels = &device.values();
+        match readings {
+            Reading::Aranet4(readings) => {
+                self.co2.with_label_values(labels).set(readings.c02 as i64);
+                self.temp_f
+                    .with_label_values(labels)
+                    .set(readings.temp.f_float());
+                self.temp_c
+                    .with_label_values(labels)
+                    .set(readings.temp.c_float());
+                self.relative_humidity
+                    .with_label_values(labels)
+                    .set(readings.humidity as f64);
+                self.preasure
+                    .with_label_values(labels)
+                    .set(readings.preasure as f64 / 10.0);
+                self.bat.with_label_values(labels).set(readings.bat as i64);
+            }
+            Reading::Aranet2(readings) => {
+                self.temp_f
+                    .with_label_values(labels)
+                    .set(readings.temp.f_float());
+                self.temp_c
+                    .with_label_values(labels)
+                    .set(readings.temp.c_float());
+                self.relative_humidity
+                    .with_label_values(labels)
+                    .set(readings.humidity_float());
+                self.bat.with_label_values(labels).set(readings.bat as i64);
+            }
+            Reading::Radiation(readings) => {
+                self.dose_rate
+                    .with_label_values(labels)
+                    .set(readings.dose_rate_float());
+                self.dose
+                    .with_label_values(labels)
+                    .set(readings.dose_float());
+                self.bat.with_label_values(labels).set(readings.bat as i64);
+            }
+            Reading::Radon(readings) => {
+                self.radon
+                    .with_label_values(labels)
+                    .set(readings.radon as i64);
+                self.temp_f
+                    .with_label_values(labels)
+                    .set(readings.temp.f_float());
+                self.temp_c
+                    .with_label_values(labels)
+                    .set(readings.temp.c_float());
+                self.relative_humidity
+                    .with_label_values(labels)
+                    .set(readings.humidity_float());
+                self.preasure
+                    .with_label_values(labels)
+                    .set(readings.preasure as f64 / 10.0);
+                self.bat.with_label_values(labels).set(readings.bat as i64);
+            }
+        }
+        let time = readings.measured().map_or(Utc::now(), |x| x.time);
+        self.last_update
+            .with_label_values(labels)
+            .set(time.timestamp() as f64);
+    }
+
+    pub fn set_stale(&self, device: &DeviceLabels, stale: bool) {
+        self.stale
+            .with_label_values(&device.values())
+            .set(stale as i64);
+    }
+
+    pub fn count_poll(&self, device: &DeviceLabels, new: bool) {
+        let [address, name, room] = device.values();
+        let result = if new { "new" } else { "duplicate" };
+        self.polls
+            .with_label_values(&[address, name, room, result])
+            .inc();
+    }
+
+    pub fn set_info(&self, device: &DeviceLabels, info: &DeviceInfo) {
+        let [address, name, room] = device.values();
+        self.info
+            .with_label_values(&[
+                address,
+                name,
+                room,
+                info.model_number.as_deref().unwrap_or_default(),
+                info.firmware_revision.as_deref().unwrap_or_default(),
+                info.hardware_revision.as_deref().unwrap_or_default(),
+                info.serial_number.as_deref().unwrap_or_default(),
+            ])
+            .set(1);
+    }
+}
 
 pub async fn gather_encode(
     _: Request<body::Incoming>,
@@ -0,0 +1,161 @@
+//! In-memory stand-in for a BLE device, serves scripted characteristic bytes so the protocol
+//! logic can be exercised without BlueZ or real hardware.
+
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+use uuid::Uuid;
+
+use crate::{
+    transport::{Characteristic, Peripheral},
+    Result,
+};
+
+type WriteHook = Box<dyn Fn(&[u8]) + Send + Sync>;
+
+/// Scripted failures look like BlueZ errors
+fn failed(message: &str) -> bluer::Error {
+    bluer::Error {
+        kind: bluer::ErrorKind::Failed,
+        message: message.to_string(),
+    }
+}
+
+#[derive(Default)]
+struct State {
+    /// One-shot reads, served before `value`
+    reads: VecDeque<Result<Vec<u8>, String>>,
+    /// Returned on every read once `reads` is exhausted
+    value: Option<Vec<u8>>,
+    writes: Vec<Vec<u8>>,
+    write_error: Option<String>,
+    on_write: Option<WriteHook>,
+}
+
+/// Cloning gives another handle to the same characteristic, so tests can keep one around to
+/// inspect writes or script further reads after handing it to a `MockDevice`.
+#[derive(Clone, Default)]
+pub struct MockCharacteristic {
+    state: Arc<Mutex<State>>,
+}
+
+impl std::fmt::Debug for MockCharacteristic {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let state = self.state.lock().unwrap();
+        f.debug_struct("MockCharacteristic")
+            .field("value", &state.value)
+            .field("queued_reads", &state.reads.len())
+            .field("writes", &state.writes)
+            .finish()
+    }
+}
+
+impl MockCharacteristic {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A characteristic which always reads as `value`
+    pub fn with_value(value: &[u8]) -> Self {
+        let mock = Self::new();
+        mock.set_value(value);
+        mock
+    }
+
+    pub fn set_value(&self, value: &[u8]) {
+        self.state.lock().unwrap().value = Some(value.to_vec());
+    }
+
+    pub fn value(&self) -> Option<Vec<u8>> {
+        self.state.lock().unwrap().value.clone()
+    }
+
+    /// Queues a value for a single read
+    pub fn push_read(&self, value: &[u8]) {
+        self.state
+            .lock()
+            .unwrap()
+            .reads
+            .push_back(Ok(value.to_vec()));
+    }
+
+    /// Queues a failure for a single read
+    pub fn push_read_error(&self, msg: &str) {
+        self.state
+            .lock()
+            .unwrap()
+            .reads
+            .push_back(Err(msg.to_string()));
+    }
+
+    /// Makes every following write fail
+    pub fn fail_writes(&self, msg: &str) {
+        self.state.lock().unwrap().write_error = Some(msg.to_string());
+    }
+
+    /// Called with the bytes of every successful write, used to script device side reactions
+    pub fn on_write(&self, hook: impl Fn(&[u8]) + Send + Sync + 'static) {
+        self.state.lock().unwrap().on_write = Some(Box::new(hook));
+    }
+
+    /// Every successful write so far, oldest first
+    pub fn writes(&self) -> Vec<Vec<u8>> {
+        self.state.lock().unwrap().writes.clone()
+    }
+}
+
+impl Characteristic for MockCharacteristic {
+    async fn read(&self) -> Result<Vec<u8>> {
+        let mut state = self.state.lock().unwrap();
+        match state.reads.pop_front() {
+            Some(read) => read.map_err(|e| failed(&e).into()),
+            None => state
+                .value
+                .clone()
+                .ok_or(failed("Nothing scripted for read").into()),
+        }
+    }
+
+    async fn write(&self, value: &[u8]) -> Result<()> {
+        // Take the hook out so it can freely use other handles to this characteristic
+        let hook = {
+            let mut state = self.state.lock().unwrap();
+            if let Some(e) = &state.write_error {
+                return Err(failed(e).into());
+            }
+            state.writes.push(value.to_vec());
+            state.on_write.take()
+        };
+        if let Some(hook) = hook {
+            hook(value);
+            self.state.lock().unwrap().on_write.get_or_insert(hook);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MockDevice {
+    characteristics: Vec<(Uuid, Uuid, MockCharacteristic)>,
+}
+
+impl MockDevice {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with(mut self, service: Uuid, characteristic: Uuid, mock: MockCharacteristic) -> Self {
+        self.characteristics.push((service, characteristic, mock));
+        self
+    }
+}
+
+impl Peripheral for MockDevice {
+    type Characteristic = MockCharacteristic;
+
+    async fn characteristics(&self) -> Result<Vec<(Uuid, Uuid, MockCharacteristic)>> {
+        Ok(self.characteristics.clone())
+    }
+}
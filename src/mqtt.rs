@@ -0,0 +1,317 @@
+//! MQTT publisher with Home Assistant discovery, each device's latest reading goes to a state
+//! topic as JSON with the field names and units of `--output json`.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use rumqttc::{AsyncClient, Event, EventLoop, LastWill, MqttOptions, Packet, QoS};
+use serde_json::{json, Map, Value};
+
+use crate::{metric::DeviceLabels, schedule::Backoff, sink::Sink, types::Sample, Error, Result};
+
+/// Entities announced to Home Assistant: state field, name, unit, device class
+const ENTITIES: [(&str, &str, &str, Option<&str>); 8] = [
+    ("co2_ppm", "CO2", "ppm", Some("carbon_dioxide")),
+    ("temperature_c", "Temperature", "°C", Some("temperature")),
+    ("humidity_percent", "Humidity", "%", Some("humidity")),
+    (
+        "pressure_hpa",
+        "Pressure",
+        "hPa",
+        Some("atmospheric_pressure"),
+    ),
+    ("battery_percent", "Battery", "%", Some("battery")),
+    ("radon_bq_m3", "Radon", "Bq/m³", None),
+    ("dose_rate_nsv_h", "Dose rate", "nSv/h", None),
+    ("dose_nsv", "Dose", "nSv", None),
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub topic: String,
+    pub payload: String,
+    pub retain: bool,
+    /// Carries a reading rather than config or availability
+    pub state: bool,
+}
+
+/// Where things get published
+#[derive(Debug, Clone)]
+pub struct Topics {
+    /// State topic template, takes `{device}` (name, or address without colons), `{address}`,
+    /// `{name}` and `{room}`
+    pub state: String,
+    /// Bridge availability, the last will sets it to offline
+    pub availability: String,
+    /// Home Assistant discovery prefix, nothing is announced when `None`
+    pub discovery: Option<String>,
+    devices: HashMap<String, DeviceLabels>,
+}
+
+impl Topics {
+    pub fn new(state: &str, availability: &str, discovery: Option<&str>) -> Self {
+        Self {
+            state: state.to_string(),
+            availability: availability.to_string(),
+            discovery: discovery.map(str::to_string),
+            devices: HashMap::new(),
+        }
+    }
+
+    /// Names the topics of `labels.address` after it's configured name and room
+    pub fn set_labels(&mut self, labels: &DeviceLabels) {
+        self.devices.insert(labels.address.clone(), labels.clone());
+    }
+
+    fn labels(&self, address: &str) -> (&str, &str) {
+        self.devices
+            .get(address)
+            .map_or(("", ""), |x| (x.name.as_str(), x.room.as_str()))
+    }
+
+    pub fn state_topic(&self, address: &str) -> String {
+        let (name, room) = self.labels(address);
+        let id = address.replace(':', "");
+        self.state
+            .replace("{device}", if name.is_empty() { &id } else { name })
+            .replace("{address}", &id)
+            .replace("{name}", name)
+            .replace("{room}", room)
+    }
+
+    /// Online while the device is polled, offline while it's stale
+    pub fn device_availability(&self, address: &str) -> String {
+        format!("{}/availability", self.state_topic(address))
+    }
+
+    /// Retained config messages for every value `sample` has
+    pub fn discovery(&self, sample: &Sample) -> Vec<Message> {
+        let Some(prefix) = &self.discovery else {
+            return Vec::new();
+        };
+        let (name, room) = self.labels(&sample.device);
+        let id = format!("aranet_{}", sample.device.replace(':', "").to_lowercase());
+        let model = sample.model.map(|x| x.to_string());
+        let mut device = json!({
+            "identifiers": [id],
+            "connections": [["mac", sample.device]],
+            "manufacturer": "SAF Tehnika",
+            "model": model.clone().unwrap_or("Aranet".to_string()),
+            "name": if name.is_empty() {
+                format!("{} {}", model.unwrap_or("Aranet".to_string()), sample.device)
+            } else {
+                name.to_string()
+            },
+        });
+        if !room.is_empty() {
+            device["suggested_area"] = json!(room);
+        }
+        let state = state_payload(sample);
+
+        ENTITIES
+            .iter()
+            .filter(|(field, ..)| state.get(*field).is_some_and(|x| !x.is_null()))
+            .map(|(field, entity, unit, class)| {
+                let mut config = json!({
+                    "name": entity,
+                    "unique_id": format!("{id}_{field}"),
+                    "state_topic": self.state_topic(&sample.device),
+                    "value_template": format!("{{{{ value_json.{field} }}}}"),
+                    "unit_of_measurement": unit,
+                    "state_class": "measurement",
+                    "availability": [
+                        {"topic": self.availability},
+                        {"topic": self.device_availability(&sample.device)},
+                    ],
+                    "availability_mode": "all",
+                    "device": device,
+                });
+                if let Some(class) = class {
+                    config["device_class"] = json!(class);
+                }
+                // The total dose only ever grows, Home Assistant keeps long term statistics of
+                // it as a sum instead of averages
+                if *field == "dose_nsv" {
+                    config["state_class"] = json!("total_increasing");
+                }
+                if *field == "battery_percent" {
+                    config["entity_category"] = json!("diagnostic");
+                }
+                Message {
+                    topic: format!("{prefix}/sensor/{id}/{field}/config"),
+                    payload: config.to_string(),
+                    retain: true,
+                    state: false,
+                }
+            })
+            .collect()
+    }
+}
+
+/// A sample as the JSON published on it's state topic, values it doesn't have are left out
+pub fn state_payload(sample: &Sample) -> Map<String, Value> {
+    let mut state = Map::new();
+    state.insert("device".to_string(), json!(sample.device));
+    state.insert("time".to_string(), json!(sample.time));
+    if let Some(model) = sample.model {
+        state.insert("model".to_string(), json!(model));
+    }
+    for (field, value) in [
+        ("co2_ppm", json!(sample.c02)),
+        ("temperature_c", json!(sample.temp)),
+        ("humidity_percent", json!(sample.humidity)),
+        ("pressure_hpa", json!(sample.preasure)),
+        ("battery_percent", json!(sample.bat)),
+        ("radon_bq_m3", json!(sample.radon)),
+        ("dose_rate_nsv_h", json!(sample.dose_rate_nsv_h())),
+        ("dose_nsv", json!(sample.dose_nsv())),
+    ] {
+        if !value.is_null() {
+            state.insert(field.to_string(), value);
+        }
+    }
+    state
+}
+
+/// Works out what to publish, kept apart from the connection so it can be tested without a
+/// broker
+#[derive(Debug, Clone)]
+pub struct Publisher {
+    pub topics: Topics,
+    /// Retain state messages so new subscribers get the last values right away
+    pub retain: bool,
+    announced: HashSet<String>,
+    last: HashMap<String, DateTime<Utc>>,
+    online: HashMap<String, bool>,
+}
+
+impl Publisher {
+    pub fn new(topics: Topics) -> Self {
+        Self {
+            topics,
+            retain: true,
+            announced: HashSet::new(),
+            last: HashMap::new(),
+            online: HashMap::new(),
+        }
+    }
+
+    /// Only the newest sample of each device is published, and only when it's newer than what
+    /// was published before, so backfilled history doesn't replace current values. Devices are
+    /// announced with their first sample.
+    pub fn messages(&mut self, samples: &[Sample]) -> Vec<Message> {
+        let mut newest: BTreeMap<&str, &Sample> = BTreeMap::new();
+        for sample in samples {
+            let entry = newest.entry(&sample.device).or_insert(sample);
+            if sample.time > entry.time {
+                *entry = sample;
+            }
+        }
+
+        let mut messages = Vec::new();
+        for (device, sample) in newest {
+            if self.last.get(device).is_some_and(|x| *x >= sample.time) {
+                continue;
+            }
+            self.last.insert(device.to_string(), sample.time);
+            if self.announced.insert(device.to_string()) {
+                messages.extend(self.topics.discovery(sample));
+            }
+            messages.extend(self.set_online(device, true));
+            messages.push(Message {
+                topic: self.topics.state_topic(device),
+                payload: Value::Object(state_payload(sample)).to_string(),
+                retain: self.retain,
+                state: true,
+            });
+        }
+        messages
+    }
+
+    /// `None` when the device's availability didn't change
+    pub fn set_online(&mut self, device: &str, online: bool) -> Option<Message> {
+        if self.online.insert(device.to_string(), online) == Some(online) {
+            return None;
+        }
+        Some(Message {
+            topic: self.topics.device_availability(device),
+            payload: if online { "online" } else { "offline" }.to_string(),
+            retain: true,
+            state: false,
+        })
+    }
+}
+
+pub struct Mqtt {
+    client: AsyncClient,
+    publisher: Publisher,
+}
+
+impl Mqtt {
+    /// Connects in the background and keeps reconnecting, the bridge availability topic goes
+    /// online on every connect and offline through the last will. Needs a tokio runtime.
+    pub fn start(mut options: MqttOptions, publisher: Publisher) -> Self {
+        let availability = publisher.topics.availability.clone();
+        options.set_last_will(LastWill::new(
+            &availability,
+            "offline",
+            QoS::AtLeastOnce,
+            true,
+        ));
+        let (client, eventloop) = AsyncClient::new(options, 1000);
+        tokio::spawn(drive(eventloop, client.clone(), availability));
+        Self { client, publisher }
+    }
+
+    fn send(&self, messages: Vec<Message>) -> Result<()> {
+        for x in messages {
+            self.client
+                .try_publish(x.topic, QoS::AtLeastOnce, x.retain, x.payload)
+                .map_err(|e| Error::Mqtt(e.to_string()))?;
+        }
+        Ok(())
+    }
+}
+
+async fn drive(mut eventloop: EventLoop, client: AsyncClient, availability: String) {
+    let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(60));
+    loop {
+        match eventloop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                eprintln!("mqtt: connected");
+                backoff.reset();
+                if let Err(e) = client.try_publish(&availability, QoS::AtLeastOnce, true, "online")
+                {
+                    eprintln!("mqtt: {e}");
+                }
+            }
+            Ok(_) => {}
+            Err(e) => {
+                let delay = backoff.next_delay();
+                eprintln!("mqtt: {e}, reconnecting in {}s", delay.as_secs());
+                tokio::time::sleep(delay).await;
+            }
+        }
+    }
+}
+
+impl Sink for Mqtt {
+    fn name(&self) -> &'static str {
+        "mqtt"
+    }
+
+    fn write(&mut self, samples: &[Sample]) -> Result<usize> {
+        let messages = self.publisher.messages(samples);
+        let published = messages.iter().filter(|x| x.state).count();
+        self.send(messages)?;
+        Ok(published)
+    }
+
+    fn set_stale(&mut self, device: &str, stale: bool) -> Result<()> {
+        let message = self.publisher.set_online(device, !stale);
+        self.send(message.into_iter().collect())
+    }
+}
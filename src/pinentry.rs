@@ -0,0 +1,163 @@
+//! Minimal Assuan client for pinentry, just enough to ask for a single PIN.
+//!
+//! See <https://www.gnupg.org/documentation/manuals/assuan/> for the protocol, every request is
+//! answered by any number of `D`, `S` and `#` lines followed by `OK` or `ERR`.
+
+use std::{
+    fmt,
+    io::{self, BufRead, BufReader, Write},
+    process::{self, Stdio},
+};
+
+use crate::{Error, Result};
+
+/// gpg-error code for a cancelled operation, pinentry sends it when the dialog is dismissed
+const GPG_ERR_CANCELED: u32 = 99;
+
+/// An `ERR` reply
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AssuanError {
+    pub code: u32,
+    pub message: String,
+}
+
+impl AssuanError {
+    /// The low 16 bits are the gpg-error code, the rest is the error source
+    pub fn is_cancelled(&self) -> bool {
+        self.code & 0xffff == GPG_ERR_CANCELED
+    }
+}
+
+impl fmt::Display for AssuanError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "pinentry error {}: {}", self.code, self.message)
+    }
+}
+
+impl std::error::Error for AssuanError {}
+
+pub struct Client<R, W> {
+    reader: R,
+    writer: W,
+}
+
+impl<R: BufRead, W: Write> Client<R, W> {
+    /// Waits for the server's greeting
+    pub fn connect(reader: R, writer: W) -> Result<Self> {
+        let mut client = Self { reader, writer };
+        client.response()?;
+        Ok(client)
+    }
+
+    /// Sends a single command, returns the decoded data lines of the reply
+    pub fn command(&mut self, command: &str) -> Result<String> {
+        self.writer.write_all(command.as_bytes())?;
+        self.writer.write_all(b"\n")?;
+        self.writer.flush()?;
+        self.response()
+    }
+
+    fn response(&mut self) -> Result<String> {
+        let mut data = String::new();
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if self.reader.read_line(&mut line)? == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "pinentry closed the connection",
+                )
+                .into());
+            }
+            let line = line.trim_end_matches(['\r', '\n']);
+            let (keyword, rest) = line.split_once(' ').unwrap_or((line, ""));
+            match keyword {
+                "OK" => return Ok(data),
+                "ERR" => {
+                    let (code, message) = rest.split_once(' ').unwrap_or((rest, ""));
+                    return Err(AssuanError {
+                        code: code.parse().map_err(|_| Error::Malformed {
+                            name: "pinentry reply",
+                            reason: format!("bad error code in {line:?}"),
+                        })?,
+                        message: percent_decode(message)?,
+                    }
+                    .into());
+                }
+                "D" => data.push_str(&percent_decode(rest)?),
+                // Status, comments and the like carry nothing we need
+                _ => {}
+            }
+        }
+    }
+
+    /// Asks for a PIN, `None` when the user cancelled the dialog
+    pub fn get_pin(&mut self, title: &str, description: &str) -> Result<Option<String>> {
+        self.command(&format!("SETTITLE {}", percent_encode(title)))?;
+        self.command(&format!("SETDESC {}", percent_encode(description)))?;
+        self.command("SETPROMPT PIN:")?;
+        match self.command("GETPIN") {
+            Ok(pin) => Ok(Some(pin)),
+            Err(Error::Pinentry(e)) if e.is_cancelled() => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Runs `program` for a single PIN request, `None` when the user cancelled
+pub fn get_pin(program: &str, title: &str, description: &str) -> Result<Option<String>> {
+    let mut child = process::Command::new(program)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+
+    let (Some(stdin), Some(stdout)) = (child.stdin.take(), child.stdout.take()) else {
+        return Err(io::Error::other("pinentry stdio isn't piped").into());
+    };
+
+    let pin = Client::connect(BufReader::new(stdout), stdin).and_then(|mut client| {
+        let pin = client.get_pin(title, description)?;
+        // Failing to say goodbye doesn't matter once we have the PIN
+        let _ = client.command("BYE");
+        Ok(pin)
+    });
+    if pin.is_err() {
+        let _ = child.kill();
+    }
+    child.wait()?;
+    pin
+}
+
+/// Assuan escapes `%`, CR and LF in parameters as %XX
+pub fn percent_encode(input: &str) -> String {
+    let mut out = String::with_capacity(input.len());
+    for c in input.chars() {
+        match c {
+            '%' | '\r' | '\n' => out.push_str(&format!("%{:02X}", c as u8)),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+pub fn percent_decode(input: &str) -> Result<String> {
+    let malformed = || Error::Malformed {
+        name: "pinentry data",
+        reason: format!("bad escape in {input:?}"),
+    };
+    let mut out = Vec::with_capacity(input.len());
+    let mut bytes = input.bytes();
+    while let Some(b) = bytes.next() {
+        if b == b'%' {
+            let hex = [
+                bytes.next().ok_or_else(malformed)?,
+                bytes.next().ok_or_else(malformed)?,
+            ];
+            let hex = std::str::from_utf8(&hex).map_err(|_| malformed())?;
+            out.push(u8::from_str_radix(hex, 16).map_err(|_| malformed())?);
+        } else {
+            out.push(b);
+        }
+    }
+    String::from_utf8(out).map_err(|_| malformed())
+}
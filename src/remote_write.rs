@@ -0,0 +1,260 @@
+//! Prometheus remote-write client. Scrapes only ever see the current value, this is how
+//! timestamped samples such as history recovered after an outage get into Prometheus (with
+//! `--web.enable-remote-write-receiver`), Mimir or VictoriaMetrics. Series carry the same names
+//! and labels as the scraped gauges.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    io,
+    time::{Duration, Instant},
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+
+use crate::{
+    metric::DeviceLabels, schedule::Backoff, sink::BatchWriter, types::Sample, Error, Result,
+};
+
+/// Samples sent per request
+pub const DEFAULT_BATCH_SIZE: usize = 500;
+
+/// Oldest samples are dropped beyond this while the server is unreachable
+pub const MAX_PENDING: usize = 100_000;
+
+/// The subset of the remote-write 1.0 protobuf messages that gets sent
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct WriteRequest {
+    #[prost(message, repeated, tag = "1")]
+    pub timeseries: Vec<TimeSeries>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct TimeSeries {
+    /// Sorted by name, `__name__` included
+    #[prost(message, repeated, tag = "1")]
+    pub labels: Vec<Label>,
+    /// Oldest first
+    #[prost(message, repeated, tag = "2")]
+    pub samples: Vec<Point>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Label {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(string, tag = "2")]
+    pub value: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Point {
+    #[prost(double, tag = "1")]
+    pub value: f64,
+    /// Milliseconds since the epoch
+    #[prost(int64, tag = "2")]
+    pub timestamp: i64,
+}
+
+/// Gauge names of the values a sample can have, as exported by `metric::Gauges`
+fn values(sample: &Sample) -> [(&'static str, Option<f64>); 9] {
+    [
+        ("aranet_co2", sample.c02.map(f64::from)),
+        ("aranet_temp_celsius", sample.temp),
+        (
+            "aranet_temp_fahrenheit",
+            sample.temp.map(|x| x * 9.0 / 5.0 + 32.0),
+        ),
+        ("aranet_relative_humidity", sample.humidity),
+        ("aranet_preasure", sample.preasure),
+        ("aranet_bat", sample.bat.map(f64::from)),
+        ("aranet_radon", sample.radon.map(f64::from)),
+        ("aranet_radiation_dose_rate", sample.dose_rate),
+        ("aranet_radiation_dose", sample.dose),
+    ]
+}
+
+pub struct RemoteWrite {
+    agent: ureq::Agent,
+    url: String,
+    /// Value of the Authorization header
+    auth: Option<String>,
+    /// Added to every series
+    external_labels: BTreeMap<String, String>,
+    /// `name` and `room` by device address
+    devices: HashMap<String, DeviceLabels>,
+    batch_size: usize,
+    /// Samples not taken by the server yet, oldest first
+    pending: Vec<Sample>,
+    backoff: Backoff,
+    retry_at: Option<Instant>,
+}
+
+impl RemoteWrite {
+    pub fn new(url: &str) -> Self {
+        Self {
+            agent: ureq::AgentBuilder::new()
+                .timeout(Duration::from_secs(10))
+                .build(),
+            url: url.to_string(),
+            auth: None,
+            external_labels: BTreeMap::new(),
+            devices: HashMap::new(),
+            batch_size: DEFAULT_BATCH_SIZE,
+            pending: Vec::new(),
+            backoff: Backoff::new(Duration::from_secs(5), Duration::from_secs(5 * 60)),
+            retry_at: None,
+        }
+    }
+
+    pub fn set_basic_auth(&mut self, username: &str, password: &str) {
+        self.auth = Some(format!(
+            "Basic {}",
+            STANDARD.encode(format!("{username}:{password}"))
+        ));
+    }
+
+    pub fn set_bearer_token(&mut self, token: &str) {
+        self.auth = Some(format!("Bearer {token}"));
+    }
+
+    pub fn set_external_labels(&mut self, labels: BTreeMap<String, String>) {
+        self.external_labels = labels;
+    }
+
+    /// Labels series of `labels.address` with it's name and room
+    pub fn set_labels(&mut self, labels: &DeviceLabels) {
+        self.devices.insert(labels.address.clone(), labels.clone());
+    }
+
+    /// Most samples sent in a single request, a flush starts once this many are waiting
+    pub fn set_batch_size(&mut self, batch_size: usize) {
+        self.batch_size = batch_size.max(1);
+    }
+
+    /// One series per gauge and device, values a sample doesn't have are left out
+    pub fn request(&self, samples: &[Sample]) -> WriteRequest {
+        let mut series: BTreeMap<(&str, &str), Vec<Point>> = BTreeMap::new();
+        for sample in samples {
+            for (name, value) in values(sample) {
+                if let Some(value) = value.filter(|x| x.is_finite()) {
+                    series
+                        .entry((name, &sample.device))
+                        .or_default()
+                        .push(Point {
+                            value,
+                            timestamp: sample.time.timestamp_millis(),
+                        });
+                }
+            }
+        }
+
+        let timeseries = series
+            .into_iter()
+            .map(|((name, device), mut samples)| {
+                samples.sort_by_key(|x| x.timestamp);
+                let mut labels = self.external_labels.clone();
+                labels.insert("__name__".to_string(), name.to_string());
+                labels.insert("address".to_string(), device.to_string());
+                if let Some(device) = self.devices.get(device) {
+                    for (key, value) in [("name", &device.name), ("room", &device.room)] {
+                        if !value.is_empty() {
+                            labels.insert(key.to_string(), value.clone());
+                        }
+                    }
+                }
+                TimeSeries {
+                    labels: labels
+                        .into_iter()
+                        .map(|(name, value)| Label { name, value })
+                        .collect(),
+                    samples,
+                }
+            })
+            .collect();
+        WriteRequest { timeseries }
+    }
+
+    fn post(&self, samples: &[Sample]) -> std::result::Result<(), Box<ureq::Error>> {
+        let body = prost::Message::encode_to_vec(&self.request(samples));
+        let body = snap::raw::Encoder::new()
+            .compress_vec(&body)
+            .map_err(|e| Box::new(io::Error::from(e).into()))?;
+        let mut request = self
+            .agent
+            .post(&self.url)
+            .set("Content-Encoding", "snappy")
+            .set("Content-Type", "application/x-protobuf")
+            .set("X-Prometheus-Remote-Write-Version", "0.1.0");
+        if let Some(auth) = &self.auth {
+            request = request.set("Authorization", auth);
+        }
+        request.send_bytes(&body)?;
+        Ok(())
+    }
+}
+
+impl BatchWriter for RemoteWrite {
+    /// Only kept in memory, past `MAX_PENDING` the oldest samples are dropped
+    fn queue(&mut self, samples: &[Sample]) -> Result<()> {
+        self.pending.extend_from_slice(samples);
+        if self.pending.len() > MAX_PENDING {
+            let n = self.pending.len() - MAX_PENDING;
+            eprintln!("remote_write: dropping the {n} oldest samples, too many pending");
+            self.pending.drain(..n);
+        }
+        Ok(())
+    }
+
+    fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    fn batch_size(&self) -> usize {
+        self.batch_size
+    }
+
+    /// Sends everything pending in batches of `batch_size`. Batches the server rejects as bad
+    /// data, such as samples older than it accepts, are dropped, anything else is kept for
+    /// the next try.
+    fn flush(&mut self) -> Result<usize> {
+        let mut sent = 0;
+        let res = loop {
+            if self.pending.is_empty() {
+                break Ok(sent);
+            }
+            let n = self.batch_size.min(self.pending.len());
+            match self.post(&self.pending[..n]).map_err(|e| *e) {
+                Ok(()) => sent += n,
+                Err(ureq::Error::Status(code @ 400..=428 | code @ 430..=499, response)) => {
+                    let body = response.into_string().unwrap_or_default();
+                    eprintln!(
+                        "remote_write: dropping {n} samples the server rejected ({code}): {body}"
+                    );
+                }
+                Err(e) => break Err(Error::Http(describe(e))),
+            }
+            self.pending.drain(..n);
+        };
+        match res {
+            Ok(_) => {
+                self.backoff.reset();
+                self.retry_at = None;
+            }
+            Err(_) => self.retry_at = Some(Instant::now() + self.backoff.next_delay()),
+        }
+        res
+    }
+
+    fn retry_at(&self) -> Option<Instant> {
+        self.retry_at
+    }
+}
+
+fn describe(e: ureq::Error) -> String {
+    match e {
+        ureq::Error::Status(code, response) => {
+            format!("{code}: {}", response.into_string().unwrap_or_default())
+        }
+        ureq::Error::Transport(e) => e.to_string(),
+    }
+}
@@ -0,0 +1,112 @@
+//! Times polls to land just after the device takes a measurement, rather than on a fixed period
+//! which either re-reads a stale value or picks up a new one late, and spaces out reconnection
+//! attempts.
+
+use std::{fmt, time::Duration};
+
+use chrono::{DateTime, TimeDelta, Utc};
+
+use crate::types::Measured;
+
+/// Slack after the expected measurement, the new value isn't always readable right away
+pub const MARGIN: Duration = Duration::from_secs(2);
+
+/// How long to wait from `now` until just after the measurement following `measured`, `None`
+/// when the interval is unknown
+pub fn next_poll(measured: Measured, now: DateTime<Utc>) -> Option<Duration> {
+    if measured.interval == 0 {
+        return None;
+    }
+    let interval = TimeDelta::seconds(measured.interval as i64);
+    let mut next = measured.time + interval;
+    // Skip measurements which already happened, e.g. after a slow reconnect
+    if next <= now {
+        let missed = (now - next).num_seconds() / interval.num_seconds() + 1;
+        next += interval * missed as i32;
+    }
+    Some((next - now).to_std().unwrap_or_default() + MARGIN)
+}
+
+/// Counts polls which returned a new measurement against ones which saw the previous one again
+#[derive(Debug, Default, Clone)]
+pub struct PollStats {
+    pub new: u64,
+    pub duplicate: u64,
+    last: Option<DateTime<Utc>>,
+}
+
+impl PollStats {
+    /// Returns false when `measured` is the same measurement as the last recorded one, polls
+    /// without a measurement time can't be told apart and always count as new
+    pub fn record(&mut self, measured: Option<Measured>) -> bool {
+        let Some(measured) = measured else {
+            self.new += 1;
+            return true;
+        };
+        // Times derived from seconds_since_update jitter by a second or so between reads
+        let tolerance = TimeDelta::seconds(measured.interval as i64 / 2);
+        let duplicate = self
+            .last
+            .is_some_and(|last| (measured.time - last).abs() < tolerance);
+        if duplicate {
+            self.duplicate += 1;
+        } else {
+            self.new += 1;
+            self.last = Some(measured.time);
+        }
+        !duplicate
+    }
+
+    /// Measurement time of the newest poll with new data
+    pub fn last(&self) -> Option<DateTime<Utc>> {
+        self.last
+    }
+}
+
+impl fmt::Display for PollStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} new, {} duplicate", self.new, self.duplicate)
+    }
+}
+
+/// Jittered exponential backoff, used between reconnection attempts so a room full of
+/// exporters doesn't hammer BlueZ in lockstep
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    base: Duration,
+    max: Duration,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new(base: Duration, max: Duration) -> Self {
+        Self {
+            base,
+            max,
+            attempt: 0,
+        }
+    }
+
+    /// Upper bound of the next delay, doubles with every attempt up to `max`
+    pub fn ceiling(&self) -> Duration {
+        self.base
+            .saturating_mul(1 << self.attempt.min(16))
+            .min(self.max)
+    }
+
+    /// Somewhere between half the ceiling and the ceiling
+    pub fn next_delay(&mut self) -> Duration {
+        let ceiling = self.ceiling();
+        self.attempt += 1;
+        ceiling / 2 + ceiling.mul_f64(rand::random_range(0.0..0.5))
+    }
+
+    pub fn attempts(&self) -> u32 {
+        self.attempt
+    }
+
+    /// Called once a connection succeeds
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
@@ -0,0 +1,144 @@
+//! Backends which take samples with their own timestamps, unlike the Prometheus gauges which
+//! only ever hold the latest value. Readings collected by `service` and history recovered
+//! after an outage both end up here.
+
+use std::{
+    io,
+    sync::mpsc::{self, RecvTimeoutError},
+    thread,
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Utc};
+
+use crate::{store::Store, types::Sample, Result};
+
+pub trait Sink: Send {
+    fn name(&self) -> &'static str;
+
+    /// Returns how many samples were new to the backend
+    fn write(&mut self, samples: &[Sample]) -> Result<usize>;
+
+    /// Time of the newest sample the backend holds for `device`, `None` when it can't tell
+    fn latest(&self, _device: &str) -> Result<Option<DateTime<Utc>>> {
+        Ok(None)
+    }
+
+    /// For backends which show whether a device is currently being read
+    fn set_stale(&mut self, _device: &str, _stale: bool) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl Sink for Store {
+    fn name(&self) -> &'static str {
+        "store"
+    }
+
+    fn write(&mut self, samples: &[Sample]) -> Result<usize> {
+        self.insert_all(samples)
+    }
+
+    fn latest(&self, device: &str) -> Result<Option<DateTime<Utc>>> {
+        Store::latest(self, device)
+    }
+}
+
+/// Backends which send samples over the network in batches, driven from a thread of their own
+/// by `Batched`
+pub trait BatchWriter: Send + 'static {
+    /// Takes samples in without sending them
+    fn queue(&mut self, samples: &[Sample]) -> Result<()>;
+
+    /// Samples waiting to be sent
+    fn pending(&self) -> usize;
+
+    /// A flush starts as soon as this many samples are waiting
+    fn batch_size(&self) -> usize;
+
+    /// Sends everything pending, returns how many samples the server took
+    fn flush(&mut self) -> Result<usize>;
+
+    /// When a failed flush may be tried again
+    fn retry_at(&self) -> Option<Instant>;
+}
+
+/// Hands samples to a `BatchWriter` on a thread of it's own, which flushes every `interval` or
+/// as soon as a batch is full, so a slow or unreachable server never holds up polling
+pub struct Batched {
+    name: &'static str,
+    sender: mpsc::Sender<Vec<Sample>>,
+}
+
+impl Batched {
+    pub fn start<W: BatchWriter>(name: &'static str, mut writer: W, interval: Duration) -> Self {
+        let (sender, receiver) = mpsc::channel::<Vec<Sample>>();
+        thread::spawn(move || {
+            let mut next = Instant::now() + interval;
+            loop {
+                let wake = writer.retry_at().unwrap_or(next);
+                match receiver.recv_timeout(wake.saturating_duration_since(Instant::now())) {
+                    Ok(samples) => {
+                        if let Err(e) = writer.queue(&samples) {
+                            eprintln!("{name}: buffering failed: {e}");
+                        }
+                        if writer.pending() < writer.batch_size() && Instant::now() < wake {
+                            continue;
+                        }
+                    }
+                    Err(RecvTimeoutError::Timeout) => {}
+                    // Nothing more will come, whatever is pending stays buffered
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+                if writer.retry_at().is_some_and(|x| Instant::now() < x) {
+                    continue;
+                }
+                next = Instant::now() + interval;
+                if writer.pending() == 0 {
+                    continue;
+                }
+                if let Err(e) = writer.flush() {
+                    eprintln!(
+                        "{name}: {e}, {} points buffered, retrying in {}s",
+                        writer.pending(),
+                        writer
+                            .retry_at()
+                            .map_or(0, |x| x.saturating_duration_since(Instant::now()).as_secs())
+                    );
+                }
+            }
+        });
+        Self { name, sender }
+    }
+}
+
+impl Sink for Batched {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Only hands the samples over, returns how many were
+    fn write(&mut self, samples: &[Sample]) -> Result<usize> {
+        self.sender.send(samples.to_vec()).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                format!("{} writer stopped", self.name),
+            )
+        })?;
+        Ok(samples.len())
+    }
+}
+
+/// Writes to every sink, one failing doesn't keep the samples from the others. Returns how
+/// many samples each sink took, in order.
+pub fn write_all(sinks: &mut [Box<dyn Sink>], samples: &[Sample]) -> Vec<Result<usize>> {
+    sinks.iter_mut().map(|x| x.write(samples)).collect()
+}
+
+/// Newest time any of the sinks holds for `device`
+pub fn latest(sinks: &[Box<dyn Sink>], device: &str) -> Option<DateTime<Utc>> {
+    sinks
+        .iter()
+        .filter_map(|x| x.latest(device).ok().flatten())
+        .max()
+}
@@ -0,0 +1,203 @@
+//! Local SQLite history of readings, keyed by device and measurement time.
+
+use std::path::Path;
+
+use chrono::{DateTime, TimeDelta, Utc};
+use rusqlite::{params, Connection, OptionalExtension, Row};
+
+use crate::{
+    types::{Sample, SyncState},
+    Error, Result,
+};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS readings (
+    device TEXT NOT NULL,
+    time INTEGER NOT NULL,
+    model TEXT,
+    c02 INTEGER,
+    temp REAL,
+    humidity REAL,
+    preasure REAL,
+    bat INTEGER,
+    radon INTEGER,
+    dose_rate REAL,
+    dose REAL,
+    PRIMARY KEY (device, time)
+);
+CREATE TABLE IF NOT EXISTS history_sync (
+    device TEXT PRIMARY KEY,
+    interval INTEGER NOT NULL,
+    newest INTEGER NOT NULL
+);
+";
+
+/// Measurement times are worked out from the device's seconds since update, so the same
+/// measurement read twice can land a second or two apart
+pub const DEFAULT_TOLERANCE: TimeDelta = TimeDelta::seconds(10);
+
+pub struct Store {
+    conn: Connection,
+    tolerance: TimeDelta,
+}
+
+impl Store {
+    /// Creates the database and it's parent directory if needed
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        if let Some(dir) = path.as_ref().parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        Self::init(Connection::open(path)?)
+    }
+
+    pub fn open_in_memory() -> Result<Self> {
+        Self::init(Connection::open_in_memory()?)
+    }
+
+    fn init(conn: Connection) -> Result<Self> {
+        conn.execute_batch(SCHEMA)?;
+        Ok(Self {
+            conn,
+            tolerance: DEFAULT_TOLERANCE,
+        })
+    }
+
+    /// Samples of a device closer than `tolerance` to a stored one count as duplicates
+    pub fn set_tolerance(&mut self, tolerance: TimeDelta) {
+        self.tolerance = tolerance;
+    }
+
+    /// Returns false if the sample was a duplicate
+    pub fn insert(&mut self, sample: &Sample) -> Result<bool> {
+        Ok(self.insert_all(std::slice::from_ref(sample))? == 1)
+    }
+
+    /// Inserts in a single transaction, returns how many samples weren't duplicates
+    pub fn insert_all(&mut self, samples: &[Sample]) -> Result<usize> {
+        let tolerance = self.tolerance.num_seconds();
+        let tx = self.conn.transaction()?;
+        let mut inserted = 0;
+        {
+            let mut exists = tx.prepare_cached(
+                "SELECT 1 FROM readings WHERE device = ?1 AND time BETWEEN ?2 - ?3 AND ?2 + ?3",
+            )?;
+            let mut insert = tx.prepare_cached(
+                "INSERT OR IGNORE INTO readings
+                 (device, time, model, c02, temp, humidity, preasure, bat, radon, dose_rate, dose)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+            )?;
+            for x in samples {
+                let time = x.time.timestamp();
+                if exists.exists(params![x.device, time, tolerance])? {
+                    continue;
+                }
+                inserted += insert.execute(params![
+                    x.device,
+                    time,
+                    x.model.map(|x| x.to_string()),
+                    x.c02,
+                    x.temp,
+                    x.humidity,
+                    x.preasure,
+                    x.bat,
+                    x.radon,
+                    x.dose_rate,
+                    x.dose,
+                ])?;
+            }
+        }
+        tx.commit()?;
+        Ok(inserted)
+    }
+
+    /// Samples within `[from, to]`, for every device when `device` is `None`, ordered by
+    /// device then time
+    pub fn query(
+        &self,
+        device: Option<&str>,
+        from: Option<DateTime<Utc>>,
+        to: Option<DateTime<Utc>>,
+    ) -> Result<Vec<Sample>> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT device, time, model, c02, temp, humidity, preasure, bat, radon, dose_rate, dose
+             FROM readings
+             WHERE (?1 IS NULL OR device = ?1) AND time >= ?2 AND time <= ?3
+             ORDER BY device, time",
+        )?;
+        let rows = stmt.query_map(
+            params![
+                device,
+                from.map_or(i64::MIN, |x| x.timestamp()),
+                to.map_or(i64::MAX, |x| x.timestamp()),
+            ],
+            sample_from_row,
+        )?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Time of the newest stored sample of `device`
+    pub fn latest(&self, device: &str) -> Result<Option<DateTime<Utc>>> {
+        let time: Option<i64> = self
+            .conn
+            .query_row(
+                "SELECT MAX(time) FROM readings WHERE device = ?1",
+                params![device],
+                |row| row.get(0),
+            )
+            .optional()?
+            .flatten();
+        time.map(timestamp).transpose()
+    }
+
+    /// Where the last history sync of `device` left off
+    pub fn sync_state(&self, device: &str) -> Result<Option<SyncState>> {
+        let row: Option<(u16, i64)> = self
+            .conn
+            .query_row(
+                "SELECT interval, newest FROM history_sync WHERE device = ?1",
+                params![device],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+        row.map(|(interval, newest)| {
+            Ok(SyncState {
+                interval,
+                newest: timestamp(newest)?,
+            })
+        })
+        .transpose()
+    }
+
+    pub fn set_sync_state(&self, device: &str, state: &SyncState) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO history_sync (device, interval, newest) VALUES (?1, ?2, ?3)",
+            params![device, state.interval, state.newest.timestamp()],
+        )?;
+        Ok(())
+    }
+}
+
+fn timestamp(secs: i64) -> Result<DateTime<Utc>> {
+    DateTime::from_timestamp(secs, 0).ok_or(Error::Malformed {
+        name: "stored time",
+        reason: format!("{secs} is out of range"),
+    })
+}
+
+fn sample_from_row(row: &Row) -> rusqlite::Result<Sample> {
+    let time: i64 = row.get(1)?;
+    let model: Option<String> = row.get(2)?;
+    Ok(Sample {
+        device: row.get(0)?,
+        time: DateTime::from_timestamp(time, 0).unwrap_or_default(),
+        model: model.and_then(|x| x.parse().ok()),
+        c02: row.get(3)?,
+        temp: row.get(4)?,
+        humidity: row.get(5)?,
+        preasure: row.get(6)?,
+        bat: row.get(7)?,
+        radon: row.get(8)?,
+        dose_rate: row.get(9)?,
+        dose: row.get(10)?,
+    })
+}
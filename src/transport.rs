@@ -0,0 +1,48 @@
+use std::future::Future;
+
+use bluer::{gatt::remote, Device};
+use uuid::Uuid;
+
+use crate::Result;
+
+/// A single GATT characteristic, all protocol logic in `bluetooth` goes through this
+pub trait Characteristic: Send + Sync {
+    fn read(&self) -> impl Future<Output = Result<Vec<u8>>> + Send;
+    fn write(&self, value: &[u8]) -> impl Future<Output = Result<()>> + Send;
+}
+
+/// Something exposing GATT services, normally a connected `bluer::Device`
+pub trait Peripheral: Send + Sync {
+    type Characteristic: Characteristic;
+
+    /// Every characteristic the peripheral exposes as (service uuid, characteristic uuid, characteristic)
+    fn characteristics(
+        &self,
+    ) -> impl Future<Output = Result<Vec<(Uuid, Uuid, Self::Characteristic)>>> + Send;
+}
+
+impl Characteristic for remote::Characteristic {
+    async fn read(&self) -> Result<Vec<u8>> {
+        Ok(remote::Characteristic::read(self).await?)
+    }
+
+    async fn write(&self, value: &[u8]) -> Result<()> {
+        Ok(remote::Characteristic::write(self, value).await?)
+    }
+}
+
+impl Peripheral for Device {
+    type Characteristic = remote::Characteristic;
+
+    async fn characteristics(&self) -> Result<Vec<(Uuid, Uuid, remote::Characteristic)>> {
+        let mut found = Vec::new();
+        for service in self.services().await? {
+            let service_uuid = service.uuid().await?;
+            for characteristic in service.characteristics().await? {
+                let characteristic_uuid = characteristic.uuid().await?;
+                found.push((service_uuid, characteristic_uuid, characteristic));
+            }
+        }
+        Ok(found)
+    }
+}
@@ -1,6 +1,9 @@
+use chrono::{DateTime, Local, TimeDelta, Utc};
+use serde::{Serialize, Serializer};
 use std::{
     fmt::{Display, Error as FmtError, Formatter},
     result::Result as StdResult,
+    str::FromStr,
 };
 
 #[derive(Debug)]
@@ -29,6 +32,18 @@ impl Temp {
     }
 }
 
+/// As °C
+impl Serialize for Temp {
+    fn serialize<S: Serializer>(&self, s: S) -> StdResult<S::Ok, S::Error> {
+        s.serialize_f64(self.c_float())
+    }
+}
+
+/// Values the device reports in tenths of a unit
+fn tenths<S: Serializer>(x: &u16, s: S) -> StdResult<S::Ok, S::Error> {
+    s.serialize_f64(*x as f64 / 10.0)
+}
+
 impl Display for Temp {
     fn fmt(&self, f: &mut Formatter) -> StdResult<(), FmtError> {
         write!(f, "{}", self.0)?;
@@ -36,19 +51,50 @@ impl Display for Temp {
     }
 }
 
-#[derive(Debug)]
+/// When a reading was taken by the device, as opposed to when we read it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Measured {
+    pub time: DateTime<Utc>,
+    /// Measurement interval in seconds
+    #[serde(rename = "interval_s")]
+    pub interval: u16,
+}
+
+impl Measured {
+    /// `ago` is the seconds since update the device reported alongside the sample
+    pub fn new(ago: u16, interval: u16) -> Self {
+        Self {
+            time: Utc::now() - TimeDelta::seconds(ago as i64),
+            interval,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
 pub struct CurrentReading {
+    #[serde(rename = "co2_ppm")]
     pub c02: u16,
+    #[serde(rename = "temperature_c")]
     pub temp: Temp,
+    /// 10 * hPa
+    #[serde(rename = "pressure_hpa", serialize_with = "tenths")]
     pub preasure: u16,
+    #[serde(rename = "humidity_percent")]
     pub humidity: u8,
+    #[serde(rename = "battery_percent")]
     pub bat: u8,
     pub status: u8,
+    /// Only known when read from a characteristic or advertisement carrying the sample age
+    pub measured: Option<Measured>,
 }
 
 impl CurrentReading {
     pub fn print_oneline(&self, fahrenheit: bool) {
-        println!(
+        println!("{}", self.oneline(fahrenheit));
+    }
+
+    pub fn oneline(&self, fahrenheit: bool) -> String {
+        format!(
             "{}ppm {:.2}°{} {}% {}hPa",
             self.c02,
             if fahrenheit {
@@ -59,7 +105,7 @@ impl CurrentReading {
             if fahrenheit { "F" } else { "C" },
             self.humidity,
             self.preasure / 10
-        );
+        )
     }
 }
 
@@ -79,3 +125,726 @@ impl Display for CurrentReading {
         Ok(())
     }
 }
+
+/// Current readings of an Aranet2, which only measures temperature and humidity
+#[derive(Debug, Serialize)]
+pub struct Aranet2Reading {
+    #[serde(rename = "temperature_c")]
+    pub temp: Temp,
+    /// 10 * relative humidity %
+    #[serde(rename = "humidity_percent", serialize_with = "tenths")]
+    pub humidity: u16,
+    #[serde(rename = "battery_percent")]
+    pub bat: u8,
+    pub status: u8,
+    /// Only known when read from a characteristic or advertisement carrying the sample age
+    pub measured: Option<Measured>,
+}
+
+impl Aranet2Reading {
+    pub fn humidity_float(&self) -> f64 {
+        self.humidity as f64 / 10.0
+    }
+
+    pub fn oneline(&self, fahrenheit: bool) -> String {
+        format!(
+            "{:.2}°{} {:.1}%",
+            if fahrenheit {
+                self.temp.f_float()
+            } else {
+                self.temp.c_float()
+            },
+            if fahrenheit { "F" } else { "C" },
+            self.humidity_float(),
+        )
+    }
+}
+
+impl Display for Aranet2Reading {
+    fn fmt(&self, f: &mut Formatter) -> StdResult<(), FmtError> {
+        writeln!(
+            f,
+            "Temperature: {:.2}°C / {:.2}°F",
+            self.temp.c_float(),
+            self.temp.f_float(),
+        )?;
+        writeln!(f, "Humidity:    {:.1}", self.humidity_float())?;
+        writeln!(f, "Battery:     {}", self.bat)?;
+        write!(f, "Status:      {}", self.status)?;
+        Ok(())
+    }
+}
+
+/// Current readings of an Aranet Radiation
+#[derive(Debug, Serialize)]
+pub struct RadiationReading {
+    /// nSv/h
+    #[serde(rename = "dose_rate_nsv_h")]
+    pub dose_rate: u32,
+    /// nSv accumulated over `dose_duration`
+    #[serde(rename = "dose_nsv")]
+    pub dose: u32,
+    /// Seconds
+    #[serde(rename = "dose_duration_s")]
+    pub dose_duration: u64,
+    #[serde(rename = "battery_percent")]
+    pub bat: u8,
+    pub status: u8,
+    /// Only known when read from a characteristic or advertisement carrying the sample age
+    pub measured: Option<Measured>,
+}
+
+impl RadiationReading {
+    /// µSv/h
+    pub fn dose_rate_float(&self) -> f64 {
+        self.dose_rate as f64 / 1000.0
+    }
+
+    /// mSv
+    pub fn dose_float(&self) -> f64 {
+        self.dose as f64 / 1_000_000.0
+    }
+
+    pub fn oneline(&self) -> String {
+        format!(
+            "{:.2}µSv/h {:.4}mSv",
+            self.dose_rate_float(),
+            self.dose_float()
+        )
+    }
+}
+
+impl Display for RadiationReading {
+    fn fmt(&self, f: &mut Formatter) -> StdResult<(), FmtError> {
+        writeln!(f, "Dose rate:   {:.2}µSv/h", self.dose_rate_float())?;
+        writeln!(
+            f,
+            "Dose:        {:.4}mSv over {}h",
+            self.dose_float(),
+            self.dose_duration / 3600
+        )?;
+        writeln!(f, "Battery:     {}", self.bat)?;
+        write!(f, "Status:      {}", self.status)?;
+        Ok(())
+    }
+}
+
+/// Current readings of an Aranet Radon Plus
+#[derive(Debug, Serialize)]
+pub struct RadonReading {
+    /// Bq/m³
+    #[serde(rename = "radon_bq_m3")]
+    pub radon: u32,
+    #[serde(rename = "temperature_c")]
+    pub temp: Temp,
+    /// 10 * hPa
+    #[serde(rename = "pressure_hpa", serialize_with = "tenths")]
+    pub preasure: u16,
+    /// 10 * relative humidity %
+    #[serde(rename = "humidity_percent", serialize_with = "tenths")]
+    pub humidity: u16,
+    #[serde(rename = "battery_percent")]
+    pub bat: u8,
+    pub status: u8,
+    /// Only known when read from a characteristic or advertisement carrying the sample age
+    pub measured: Option<Measured>,
+}
+
+impl RadonReading {
+    pub fn humidity_float(&self) -> f64 {
+        self.humidity as f64 / 10.0
+    }
+
+    pub fn oneline(&self, fahrenheit: bool) -> String {
+        format!(
+            "{}Bq/m³ {:.2}°{} {:.1}% {}hPa",
+            self.radon,
+            if fahrenheit {
+                self.temp.f_float()
+            } else {
+                self.temp.c_float()
+            },
+            if fahrenheit { "F" } else { "C" },
+            self.humidity_float(),
+            self.preasure / 10
+        )
+    }
+}
+
+impl Display for RadonReading {
+    fn fmt(&self, f: &mut Formatter) -> StdResult<(), FmtError> {
+        writeln!(f, "Radon:       {}", self.radon)?;
+        writeln!(
+            f,
+            "Temperature: {:.2}°C / {:.2}°F",
+            self.temp.c_float(),
+            self.temp.f_float(),
+        )?;
+        writeln!(f, "Humidity:    {:.1}", self.humidity_float())?;
+        writeln!(f, "Presure:     {}", self.preasure)?;
+        writeln!(f, "Battery:     {}", self.bat)?;
+        write!(f, "Status:      {}", self.status)?;
+        Ok(())
+    }
+}
+
+/// Serialized as the `Display` names
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum DeviceModel {
+    Aranet4,
+    Aranet2,
+    #[serde(rename = "Aranet Radiation")]
+    AranetRadiation,
+    #[serde(rename = "Aranet Radon Plus")]
+    AranetRadonPlus,
+}
+
+impl Display for DeviceModel {
+    fn fmt(&self, f: &mut Formatter) -> StdResult<(), FmtError> {
+        match self {
+            DeviceModel::Aranet4 => write!(f, "Aranet4"),
+            DeviceModel::Aranet2 => write!(f, "Aranet2"),
+            DeviceModel::AranetRadiation => write!(f, "Aranet Radiation"),
+            DeviceModel::AranetRadonPlus => write!(f, "Aranet Radon Plus"),
+        }
+    }
+}
+
+impl FromStr for DeviceModel {
+    type Err = String;
+
+    /// Takes the `Display` names
+    fn from_str(s: &str) -> StdResult<Self, Self::Err> {
+        match s {
+            "Aranet4" => Ok(DeviceModel::Aranet4),
+            "Aranet2" => Ok(DeviceModel::Aranet2),
+            "Aranet Radiation" => Ok(DeviceModel::AranetRadiation),
+            "Aranet Radon Plus" => Ok(DeviceModel::AranetRadonPlus),
+            _ => Err(format!("Unknown model {s}")),
+        }
+    }
+}
+
+/// Current readings of any supported model, serialized with a `model` field holding the
+/// `DeviceModel` name
+#[derive(Debug, Serialize)]
+#[serde(tag = "model")]
+pub enum Reading {
+    Aranet4(CurrentReading),
+    Aranet2(Aranet2Reading),
+    #[serde(rename = "Aranet Radiation")]
+    Radiation(RadiationReading),
+    #[serde(rename = "Aranet Radon Plus")]
+    Radon(RadonReading),
+}
+
+impl Reading {
+    pub fn model(&self) -> DeviceModel {
+        match self {
+            Reading::Aranet4(_) => DeviceModel::Aranet4,
+            Reading::Aranet2(_) => DeviceModel::Aranet2,
+            Reading::Radiation(_) => DeviceModel::AranetRadiation,
+            Reading::Radon(_) => DeviceModel::AranetRadonPlus,
+        }
+    }
+
+    pub fn measured(&self) -> Option<Measured> {
+        match self {
+            Reading::Aranet4(x) => x.measured,
+            Reading::Aranet2(x) => x.measured,
+            Reading::Radiation(x) => x.measured,
+            Reading::Radon(x) => x.measured,
+        }
+    }
+
+    /// Timestamped for JSON output, `read` stands in when the device doesn't say when it measured
+    pub fn timed(&self, read: DateTime<Utc>) -> TimedReading<'_> {
+        TimedReading {
+            time: self.measured().map_or(read, |x| x.time),
+            reading: self,
+        }
+    }
+
+    pub fn print_oneline(&self, fahrenheit: bool) {
+        println!("{}", self.oneline(fahrenheit));
+    }
+
+    /// Ends with the local measurement time when it's known
+    pub fn oneline(&self, fahrenheit: bool) -> String {
+        let line = match self {
+            Reading::Aranet4(x) => x.oneline(fahrenheit),
+            Reading::Aranet2(x) => x.oneline(fahrenheit),
+            Reading::Radiation(x) => x.oneline(),
+            Reading::Radon(x) => x.oneline(fahrenheit),
+        };
+        match self.measured() {
+            Some(m) => format!("{line} {}", m.time.with_timezone(&Local).format("%H:%M:%S")),
+            None => line,
+        }
+    }
+}
+
+impl Display for Reading {
+    fn fmt(&self, f: &mut Formatter) -> StdResult<(), FmtError> {
+        match self {
+            Reading::Aranet4(x) => x.fmt(f)?,
+            Reading::Aranet2(x) => x.fmt(f)?,
+            Reading::Radiation(x) => x.fmt(f)?,
+            Reading::Radon(x) => x.fmt(f)?,
+        }
+        if let Some(m) = self.measured() {
+            write!(
+                f,
+                "\nMeasured:    {} ({}s ago, every {}s)",
+                m.time.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S"),
+                (Utc::now() - m.time).num_seconds(),
+                m.interval
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// A reading as printed by `--output json`, `time` is when it was measured, or when it was read
+/// when the device doesn't say
+#[derive(Debug, Serialize)]
+pub struct TimedReading<'a> {
+    pub time: DateTime<Utc>,
+    #[serde(flatten)]
+    pub reading: &'a Reading,
+}
+
+/// Contents of the device information and battery services
+#[derive(Debug, Default, Serialize)]
+pub struct DeviceInfo {
+    pub model_number: Option<String>,
+    pub serial_number: Option<String>,
+    pub firmware_revision: Option<String>,
+    pub hardware_revision: Option<String>,
+    pub software_revision: Option<String>,
+    pub manufacturer_name: Option<String>,
+    #[serde(rename = "battery_percent")]
+    pub battery_level: Option<u8>,
+}
+
+impl Display for DeviceInfo {
+    fn fmt(&self, f: &mut Formatter) -> StdResult<(), FmtError> {
+        let unknown = String::from("-");
+        writeln!(
+            f,
+            "Model:        {}",
+            self.model_number.as_ref().unwrap_or(&unknown)
+        )?;
+        writeln!(
+            f,
+            "Serial:       {}",
+            self.serial_number.as_ref().unwrap_or(&unknown)
+        )?;
+        writeln!(
+            f,
+            "Firmware:     {}",
+            self.firmware_revision.as_ref().unwrap_or(&unknown)
+        )?;
+        writeln!(
+            f,
+            "Hardware:     {}",
+            self.hardware_revision.as_ref().unwrap_or(&unknown)
+        )?;
+        writeln!(
+            f,
+            "Software:     {}",
+            self.software_revision.as_ref().unwrap_or(&unknown)
+        )?;
+        writeln!(
+            f,
+            "Manufacturer: {}",
+            self.manufacturer_name.as_ref().unwrap_or(&unknown)
+        )?;
+        match self.battery_level {
+            Some(bat) => write!(f, "Battery:      {bat}"),
+            None => write!(f, "Battery:      {unknown}"),
+        }
+    }
+}
+
+/// Measurement intervals the device supports
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interval {
+    Min1,
+    Min2,
+    Min5,
+    Min10,
+}
+
+impl Interval {
+    pub fn minutes(&self) -> u8 {
+        match self {
+            Interval::Min1 => 1,
+            Interval::Min2 => 2,
+            Interval::Min5 => 5,
+            Interval::Min10 => 10,
+        }
+    }
+
+    pub fn from_seconds(seconds: u16) -> Option<Self> {
+        match seconds {
+            60 => Some(Interval::Min1),
+            120 => Some(Interval::Min2),
+            300 => Some(Interval::Min5),
+            600 => Some(Interval::Min10),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for Interval {
+    type Err = String;
+
+    /// Minutes, EX: 5
+    fn from_str(s: &str) -> StdResult<Self, Self::Err> {
+        match s {
+            "1" => Ok(Interval::Min1),
+            "2" => Ok(Interval::Min2),
+            "5" => Ok(Interval::Min5),
+            "10" => Ok(Interval::Min10),
+            _ => Err(format!("Invalid interval {s}, expected one of 1, 2, 5, 10")),
+        }
+    }
+}
+
+impl Display for Interval {
+    fn fmt(&self, f: &mut Formatter) -> StdResult<(), FmtError> {
+        write!(f, "{}min", self.minutes())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BluetoothRange {
+    Standard,
+    Extended,
+}
+
+impl FromStr for BluetoothRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> StdResult<Self, Self::Err> {
+        match s {
+            "standard" => Ok(BluetoothRange::Standard),
+            "extended" => Ok(BluetoothRange::Extended),
+            _ => Err(format!(
+                "Invalid range {s}, expected one of standard, extended"
+            )),
+        }
+    }
+}
+
+impl Display for BluetoothRange {
+    fn fmt(&self, f: &mut Formatter) -> StdResult<(), FmtError> {
+        match self {
+            BluetoothRange::Standard => write!(f, "standard"),
+            BluetoothRange::Extended => write!(f, "extended"),
+        }
+    }
+}
+
+/// Unit shown on the device's display
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TempUnit {
+    Celsius,
+    Fahrenheit,
+}
+
+impl FromStr for TempUnit {
+    type Err = String;
+
+    fn from_str(s: &str) -> StdResult<Self, Self::Err> {
+        match s {
+            "c" | "celsius" => Ok(TempUnit::Celsius),
+            "f" | "fahrenheit" => Ok(TempUnit::Fahrenheit),
+            _ => Err(format!("Invalid unit {s}, expected one of c, f")),
+        }
+    }
+}
+
+impl Display for TempUnit {
+    fn fmt(&self, f: &mut Formatter) -> StdResult<(), FmtError> {
+        match self {
+            TempUnit::Celsius => write!(f, "celsius"),
+            TempUnit::Fahrenheit => write!(f, "fahrenheit"),
+        }
+    }
+}
+
+/// User changeable device settings
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Settings {
+    pub interval: Interval,
+    pub smart_home: bool,
+    pub range: BluetoothRange,
+    pub temp_unit: TempUnit,
+}
+
+impl Display for Settings {
+    fn fmt(&self, f: &mut Formatter) -> StdResult<(), FmtError> {
+        writeln!(f, "Interval:         {}", self.interval)?;
+        writeln!(
+            f,
+            "Smart Home:       {}",
+            if self.smart_home { "on" } else { "off" }
+        )?;
+        writeln!(f, "Bluetooth range:  {}", self.range)?;
+        write!(f, "Temperature unit: {}", self.temp_unit)?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CalibrationState {
+    NotActive,
+    EndRequest,
+    InProgress,
+    Error,
+}
+
+impl CalibrationState {
+    pub fn from_code(code: u8) -> Self {
+        match code & 0b11 {
+            0 => CalibrationState::NotActive,
+            1 => CalibrationState::EndRequest,
+            2 => CalibrationState::InProgress,
+            _ => CalibrationState::Error,
+        }
+    }
+}
+
+impl Display for CalibrationState {
+    fn fmt(&self, f: &mut Formatter) -> StdResult<(), FmtError> {
+        match self {
+            CalibrationState::NotActive => write!(f, "not active"),
+            CalibrationState::EndRequest => write!(f, "end requested"),
+            CalibrationState::InProgress => write!(f, "in progress"),
+            CalibrationState::Error => write!(f, "error"),
+        }
+    }
+}
+
+/// CO2 sensor calibration status
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Calibration {
+    pub state: CalibrationState,
+    /// % done while a forced calibration is running
+    pub progress: u8,
+    /// Automatic baseline calibration
+    pub auto: bool,
+}
+
+impl Display for Calibration {
+    fn fmt(&self, f: &mut Formatter) -> StdResult<(), FmtError> {
+        writeln!(f, "State:            {}", self.state)?;
+        writeln!(f, "Progress:         {}%", self.progress)?;
+        write!(
+            f,
+            "Auto calibration: {}",
+            if self.auto { "on" } else { "off" }
+        )?;
+        Ok(())
+    }
+}
+
+/// A single sample from the on-device log
+#[derive(Debug, Serialize)]
+pub struct HistoryRecord {
+    pub time: DateTime<Utc>,
+    #[serde(rename = "co2_ppm")]
+    pub c02: u16,
+    #[serde(rename = "temperature_c")]
+    pub temp: Temp,
+    /// 10 * hPa
+    #[serde(rename = "pressure_hpa", serialize_with = "tenths")]
+    pub preasure: u16,
+    #[serde(rename = "humidity_percent")]
+    pub humidity: u8,
+}
+
+impl HistoryRecord {
+    pub fn print_oneline(&self, fahrenheit: bool) {
+        println!(
+            "{} {}ppm {:.2}°{} {}% {}hPa",
+            self.time.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S"),
+            self.c02,
+            if fahrenheit {
+                self.temp.f_float()
+            } else {
+                self.temp.c_float()
+            },
+            if fahrenheit { "F" } else { "C" },
+            self.humidity,
+            self.preasure / 10
+        );
+    }
+}
+
+/// Where the on-device log stands, record `i` (1 based, oldest first) was measured
+/// `(total - i) * interval` seconds before `newest`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LogState {
+    pub total: u16,
+    /// Seconds
+    pub interval: u16,
+    pub newest: DateTime<Utc>,
+}
+
+impl LogState {
+    pub fn time_of(&self, index: u16) -> DateTime<Utc> {
+        self.newest - TimeDelta::seconds(self.interval as i64 * (self.total - index) as i64)
+    }
+
+    /// Index of the first record measured after the last sync, `total + 1` when there's
+    /// nothing new.
+    ///
+    /// Worked out from time rather than remembered indices, once the log is full every new
+    /// record pushes the oldest out and shifts all indices. Changing the interval restarts the
+    /// log, so then everything on it is new.
+    pub fn first_new(&self, last: Option<&SyncState>) -> u16 {
+        let Some(last) = last.filter(|x| x.interval == self.interval && self.interval > 0) else {
+            return 1;
+        };
+        let elapsed = (self.newest - last.newest).num_seconds() as f64;
+        let new = (elapsed / self.interval as f64).round().max(0.0) as u32;
+        (self.total as u32 + 1).saturating_sub(new).max(1) as u16
+    }
+}
+
+/// What's known about a device's log after a history sync
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SyncState {
+    pub interval: u16,
+    /// Time of the newest record fetched
+    pub newest: DateTime<Utc>,
+}
+
+/// A reading of any model at a known time, what gets stored and exported. Values a model
+/// doesn't measure are `None`, units are °C, %, hPa, µSv/h and mSv.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sample {
+    /// Device address
+    pub device: String,
+    pub time: DateTime<Utc>,
+    pub model: Option<DeviceModel>,
+    pub c02: Option<u16>,
+    pub temp: Option<f64>,
+    pub humidity: Option<f64>,
+    pub preasure: Option<f64>,
+    pub bat: Option<u8>,
+    pub radon: Option<u32>,
+    pub dose_rate: Option<f64>,
+    pub dose: Option<f64>,
+}
+
+impl Sample {
+    pub fn empty(device: &str, time: DateTime<Utc>) -> Self {
+        Self {
+            device: device.to_string(),
+            time,
+            model: None,
+            c02: None,
+            temp: None,
+            humidity: None,
+            preasure: None,
+            bat: None,
+            radon: None,
+            dose_rate: None,
+            dose: None,
+        }
+    }
+
+    /// Timestamped with the measurement time when known, otherwise now
+    pub fn from_reading(device: &str, reading: &Reading) -> Self {
+        let time = reading.measured().map_or(Utc::now(), |x| x.time);
+        let mut sample = Self::empty(device, time);
+        sample.model = Some(reading.model());
+        match reading {
+            Reading::Aranet4(x) => {
+                sample.c02 = Some(x.c02);
+                sample.temp = Some(x.temp.c_float());
+                sample.humidity = Some(x.humidity as f64);
+                sample.preasure = Some(x.preasure as f64 / 10.0);
+                sample.bat = Some(x.bat);
+            }
+            Reading::Aranet2(x) => {
+                sample.temp = Some(x.temp.c_float());
+                sample.humidity = Some(x.humidity_float());
+                sample.bat = Some(x.bat);
+            }
+            Reading::Radiation(x) => {
+                sample.dose_rate = Some(x.dose_rate_float());
+                sample.dose = Some(x.dose_float());
+                sample.bat = Some(x.bat);
+            }
+            Reading::Radon(x) => {
+                sample.radon = Some(x.radon);
+                sample.temp = Some(x.temp.c_float());
+                sample.humidity = Some(x.humidity_float());
+                sample.preasure = Some(x.preasure as f64 / 10.0);
+                sample.bat = Some(x.bat);
+            }
+        }
+        sample
+    }
+
+    /// History is only downloaded from Aranet4s
+    pub fn from_history(device: &str, record: &HistoryRecord) -> Self {
+        let mut sample = Self::empty(device, record.time);
+        sample.model = Some(DeviceModel::Aranet4);
+        sample.c02 = Some(record.c02);
+        sample.temp = Some(record.temp.c_float());
+        sample.humidity = Some(record.humidity as f64);
+        sample.preasure = Some(record.preasure as f64 / 10.0);
+        sample
+    }
+
+    /// Dose rate in the nSv/h the device reports, what the JSON output and exports use
+    pub fn dose_rate_nsv_h(&self) -> Option<u32> {
+        self.dose_rate.map(|x| (x * 1000.0).round() as u32)
+    }
+
+    /// Accumulated dose in nSv
+    pub fn dose_nsv(&self) -> Option<u32> {
+        self.dose.map(|x| (x * 1_000_000.0).round() as u32)
+    }
+
+    /// Device and local time followed by the values the sample has
+    pub fn oneline(&self, fahrenheit: bool) -> String {
+        let mut line = format!(
+            "{} {}",
+            self.device,
+            self.time.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S")
+        );
+        if let Some(x) = self.c02 {
+            line += &format!(" {x}ppm");
+        }
+        if let Some(x) = self.temp {
+            if fahrenheit {
+                line += &format!(" {:.2}°F", x * 9.0 / 5.0 + 32.0);
+            } else {
+                line += &format!(" {x:.2}°C");
+            }
+        }
+        if let Some(x) = self.humidity {
+            line += &format!(" {x}%");
+        }
+        if let Some(x) = self.preasure {
+            line += &format!(" {x:.1}hPa");
+        }
+        if let Some(x) = self.radon {
+            line += &format!(" {x}Bq/m³");
+        }
+        if let Some(x) = self.dose_rate {
+            line += &format!(" {x:.2}µSv/h");
+        }
+        if let Some(x) = self.dose {
+            line += &format!(" {x:.4}mSv");
+        }
+        line
+    }
+}
@@ -0,0 +1,74 @@
+use aranet::{bluetooth::Advertisement, Error};
+
+#[test]
+fn readings_with_integrations() {
+    let bytes = [
+        0x22, 0x27, 0x00, 0x04, 0x01, 0x00, 0x0c, 0x0f, // header, v1.4.39
+        0x20, 0x03, 0xc2, 0x01, 0x94, 0x27, 45, 87, 1, // 800ppm 22.5C 1013.2hPa 45% 87%
+        0x2c, 0x01, 0x78, 0x00, 0x05, // 300s interval, 120s ago, counter 5
+    ];
+    let adv = Advertisement::parse(&bytes).unwrap();
+
+    assert!(adv.integrations());
+    assert_eq!(adv.version, (1, 4, 39));
+    let readings = adv.readings.unwrap();
+    assert_eq!(readings.c02, 800);
+    assert_eq!(readings.temp.c_float(), 22.5);
+    assert_eq!(readings.preasure, 10132);
+    assert_eq!(readings.humidity, 45);
+    assert_eq!(readings.bat, 87);
+    assert_eq!(adv.interval, Some(300));
+    assert_eq!(adv.seconds_since_update, Some(120));
+    assert_eq!(adv.counter, Some(5));
+}
+
+#[test]
+fn integrations_disabled() {
+    let adv = Advertisement::parse(&[0x02, 0x27, 0x00, 0x04, 0x01, 0x00, 0x0c, 0x0f]).unwrap();
+
+    assert!(!adv.integrations());
+    assert!(adv.readings.is_none());
+}
+
+#[test]
+fn truncated() {
+    for bytes in [
+        &[0x22, 0x27, 0x00][..],
+        &[0x00, 0x27, 0x00, 0x04][..],
+        // Integrations flag set but readings cut short
+        &[0x22, 0x27, 0x00, 0x04, 0x01, 0x00, 0x0c, 0x0f, 0x20][..],
+    ] {
+        match Advertisement::parse(bytes) {
+            Err(Error::ShortPayload { len, .. }) => assert_eq!(len, bytes.len()),
+            x => panic!("Expected {bytes:?} to be too short, got {x:?}"),
+        }
+    }
+}
+
+#[test]
+fn other_models_rejected() {
+    // Aranet2 and Aranet Radon Plus lead with their device type
+    for (bytes, model) in [
+        (
+            &[
+                0x01, 0x22, 0x27, 0x00, 0x01, 0x01, 0x00, 0x0c, 0x0f, 0x57, 0xc2, 0x01, 0xc2, 0x01,
+                0x01,
+            ][..],
+            "Aranet2",
+        ),
+        (
+            &[0x03, 0x02, 0x27, 0x00, 0x01, 0x01, 0x00, 0x0c, 0x0f][..],
+            "Aranet Radon Plus",
+        ),
+    ] {
+        match Advertisement::parse(bytes) {
+            Err(Error::Unsupported(e)) => assert!(e.contains(model), "{e}"),
+            x => panic!("Expected {model} to be rejected, got {x:?}"),
+        }
+    }
+
+    assert!(matches!(
+        Advertisement::parse(&[0x09, 0x22, 0x27, 0x00, 0x04, 0x01, 0x00, 0x0c, 0x0f, 0x00]),
+        Err(Error::Malformed { .. })
+    ));
+}
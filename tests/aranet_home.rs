@@ -0,0 +1,92 @@
+mod common;
+
+use aranet::{
+    aranet_home,
+    store::Store,
+    types::{DeviceModel, Sample},
+};
+use chrono::{DateTime, TimeDelta, Utc};
+use common::sample;
+
+/// Header row of an Aranet4 export from the app
+const APP_HEADER: &str = "\"Time(dd/mm/yyyy)\",\"Carbon dioxide(ppm)\",\"Temperature(°C)\",\"Relative humidity(%)\",\"Atmospheric pressure(hPa)\"";
+
+#[test]
+fn writes_app_layout() {
+    let t0 = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+    let samples = [
+        sample("AA:AA:AA:AA:AA:AA", t0, 650),
+        sample("AA:AA:AA:AA:AA:AA", t0 + TimeDelta::hours(10), 700),
+    ];
+    let mut out = Vec::new();
+    aranet_home::write_csv(&mut out, &samples, &Utc, false).unwrap();
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        format!(
+            "{APP_HEADER}\n\
+             14/11/2023 22:13:20,650,21.5,40,1013.2\n\
+             15/11/2023 08:13:20,700,21.5,40,1013.2\n"
+        )
+    );
+}
+
+#[test]
+fn columns_follow_the_model() {
+    let mut sample = Sample::empty("BB:BB:BB:BB:BB:BB", DateTime::from_timestamp(0, 0).unwrap());
+    sample.model = Some(DeviceModel::Aranet2);
+    sample.temp = Some(20.0);
+    sample.humidity = Some(45.5);
+    let mut out = Vec::new();
+    aranet_home::write_csv(&mut out, &[sample], &Utc, true).unwrap();
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "\"Time(dd/mm/yyyy)\",\"Temperature(°F)\",\"Relative humidity(%)\"\n\
+         01/01/1970 00:00:00,68.0,45.5\n"
+    );
+}
+
+#[test]
+fn reads_app_exports() {
+    let csv = "\"Time(dd/mm/yyyy)\",\"Carbon dioxide(ppm)\",\"Temperature(°F)\",\"Relative humidity(%)\",\"Atmospheric pressure(mmHg)\"\n\
+               14/11/2023 22:13:20,650,68,40,760\n\
+               15/11/2023 08:13,,,,\n";
+    let samples = aranet_home::read_csv(csv.as_bytes(), "AA:AA:AA:AA:AA:AA", &Utc).unwrap();
+
+    assert_eq!(samples.len(), 2);
+    assert_eq!(samples[0].device, "AA:AA:AA:AA:AA:AA");
+    assert_eq!(samples[0].model, Some(DeviceModel::Aranet4));
+    assert_eq!(samples[0].time.timestamp(), 1_700_000_000);
+    assert_eq!(samples[0].c02, Some(650));
+    assert_eq!(samples[0].temp, Some(20.0));
+    assert!((samples[0].preasure.unwrap() - 1013.25).abs() < 0.01);
+    assert_eq!(samples[1].time.timestamp(), 1_700_035_980);
+    assert_eq!(samples[1].c02, None);
+
+    assert!(aranet_home::read_csv("Time,CO2\n2023-11-14,650\n".as_bytes(), "x", &Utc).is_err());
+}
+
+#[test]
+fn round_trips_through_the_store() {
+    let t0 = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+    let mut out = Vec::new();
+    aranet_home::write_csv(
+        &mut out,
+        &[sample("AA:AA:AA:AA:AA:AA", t0, 650)],
+        &Utc,
+        false,
+    )
+    .unwrap();
+
+    let mut store = Store::open_in_memory().unwrap();
+    // Already synced over BLE a few seconds off
+    store
+        .insert(&sample(
+            "AA:AA:AA:AA:AA:AA",
+            t0 + TimeDelta::seconds(3),
+            650,
+        ))
+        .unwrap();
+    let mut imported = aranet_home::read_csv(out.as_slice(), "AA:AA:AA:AA:AA:AA", &Utc).unwrap();
+    imported.push(sample("AA:AA:AA:AA:AA:AA", t0 + TimeDelta::minutes(5), 700));
+    assert_eq!(store.insert_all(&imported).unwrap(), 1);
+}
@@ -0,0 +1,79 @@
+mod common;
+
+use std::fs::{self, File};
+
+use aranet::{
+    columnar,
+    types::{DeviceModel, Sample},
+};
+use arrow_array::{
+    cast::AsArray,
+    types::{UInt16Type, UInt32Type},
+};
+use chrono::{DateTime, TimeDelta};
+use common::{at, sample};
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+#[test]
+fn partitions_by_device_and_day() {
+    let dir = std::env::temp_dir().join(format!("aranet-columnar-{}", std::process::id()));
+    let day = TimeDelta::days(1).num_seconds();
+    // 2023-11-14 22:13:20 UTC
+    let t0 = 1_700_000_000;
+    let samples = [
+        sample("AA:AA:AA:AA:AA:AA", at(t0), 600),
+        sample("AA:AA:AA:AA:AA:AA", at(t0 + 60), 610),
+        sample("AA:AA:AA:AA:AA:AA", at(t0 + day), 620),
+        sample("BB:BB:BB:BB:BB:BB", at(t0), 700),
+    ];
+
+    let mut files = columnar::write_partitioned(&dir, &samples).unwrap();
+    files.sort();
+    assert_eq!(
+        files
+            .iter()
+            .map(|x| x.strip_prefix(&dir).unwrap().to_str().unwrap())
+            .collect::<Vec<_>>(),
+        [
+            "device=AA:AA:AA:AA:AA:AA/date=2023-11-14/data.parquet",
+            "device=AA:AA:AA:AA:AA:AA/date=2023-11-15/data.parquet",
+            "device=BB:BB:BB:BB:BB:BB/date=2023-11-14/data.parquet",
+        ]
+    );
+
+    let reader = ParquetRecordBatchReaderBuilder::try_new(File::open(&files[0]).unwrap())
+        .unwrap()
+        .build()
+        .unwrap();
+    let batches: Vec<_> = reader.map(Result::unwrap).collect();
+    assert_eq!(batches[0].schema(), columnar::schema());
+    let c02 = batches[0]
+        .column_by_name("co2_ppm")
+        .unwrap()
+        .as_primitive::<UInt16Type>();
+    assert_eq!(c02.values(), &[600, 610]);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn radiation_in_nsv() {
+    let mut sample = Sample::empty(
+        "CC:CC:CC:CC:CC:CC",
+        DateTime::from_timestamp(1_700_000_000, 0).unwrap(),
+    );
+    sample.model = Some(DeviceModel::AranetRadiation);
+    sample.dose_rate = Some(0.12);
+    sample.dose = Some(0.005);
+
+    let batch = columnar::record_batch(&[sample]).unwrap();
+    let column = |name| {
+        batch
+            .column_by_name(name)
+            .unwrap()
+            .as_primitive::<UInt32Type>()
+            .value(0)
+    };
+    assert_eq!(column("dose_rate_nsv_h"), 120);
+    assert_eq!(column("dose_nsv"), 5000);
+}
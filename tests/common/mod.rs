@@ -0,0 +1,75 @@
+//! Fixtures shared by the integration tests, not every test file uses all of them
+#![allow(dead_code)]
+
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::TcpListener,
+    sync::mpsc,
+    thread,
+};
+
+use aranet::types::{DeviceModel, Sample};
+use chrono::{DateTime, Utc};
+
+pub fn at(secs: i64) -> DateTime<Utc> {
+    DateTime::from_timestamp(secs, 0).unwrap()
+}
+
+/// An Aranet4 reading, everything but CO2 is fixed
+pub fn sample(device: &str, time: DateTime<Utc>, c02: u16) -> Sample {
+    let mut sample = Sample::empty(device, time);
+    sample.model = Some(DeviceModel::Aranet4);
+    sample.c02 = Some(c02);
+    sample.temp = Some(21.5);
+    sample.humidity = Some(40.0);
+    sample.preasure = Some(1013.2);
+    sample.bat = Some(90);
+    sample
+}
+
+/// Request line, Authorization header and body of a request the stub took
+pub type Request = (String, Vec<u8>);
+
+/// Answers each request with the next status, records what it was sent
+pub fn stub(statuses: Vec<u16>) -> (String, mpsc::Receiver<Request>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for status in statuses {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            let mut len = 0;
+            let mut headers = Vec::new();
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).unwrap();
+                if header.trim().is_empty() {
+                    break;
+                }
+                if let Some(x) = header.to_lowercase().strip_prefix("content-length:") {
+                    len = x.trim().parse().unwrap();
+                }
+                headers.push(header.trim().to_string());
+            }
+            let mut body = vec![0; len];
+            reader.read_exact(&mut body).unwrap();
+            let auth = headers
+                .iter()
+                .find(|x| x.to_lowercase().starts_with("authorization"))
+                .cloned()
+                .unwrap_or_default();
+            sender
+                .send((format!("{} {auth}", request_line.trim()), body))
+                .unwrap();
+            write!(
+                reader.get_mut(),
+                "HTTP/1.1 {status} X\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+            )
+            .unwrap();
+        }
+    });
+    (url, receiver)
+}
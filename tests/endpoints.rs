@@ -0,0 +1,628 @@
+use aranet::{
+    bluetooth::{map_device_endpoints, uuids::*, Command, HistoryPacket, HistoryParam},
+    mock::{MockCharacteristic, MockDevice},
+    types::{
+        BluetoothRange, CalibrationState, DeviceModel, Interval, LogState, Reading, Settings,
+        SyncState, TempUnit,
+    },
+    Error,
+};
+use chrono::{TimeDelta, Utc};
+
+/// Builds a history_readings_v2 packet, samples are encoded with the width `param` uses
+fn history_packet(param: HistoryParam, total: u16, start: u16, samples: &[u16]) -> Vec<u8> {
+    let mut bytes = vec![param as u8];
+    bytes.extend(60u16.to_le_bytes());
+    bytes.extend(total.to_le_bytes());
+    bytes.extend(10u16.to_le_bytes());
+    bytes.extend(start.to_le_bytes());
+    bytes.push(samples.len() as u8);
+    for sample in samples {
+        match param.sample_size() {
+            1 => bytes.push(*sample as u8),
+            _ => bytes.extend(sample.to_le_bytes()),
+        }
+    }
+    bytes
+}
+
+fn aranet4() -> MockDevice {
+    MockDevice::new()
+        .with(
+            SERVICE_SAF_TEHNIKA,
+            CHAR_CURRENT_READINGS,
+            // 800ppm, 22.5C, 1013.2hPa, 45%, 87% battery, status 1
+            MockCharacteristic::with_value(&[0x20, 0x03, 0xc2, 0x01, 0x94, 0x27, 45, 87, 1]),
+        )
+        .with(
+            SERVICE_SAF_TEHNIKA,
+            CHAR_INTERVAL,
+            MockCharacteristic::with_value(&60u16.to_le_bytes()),
+        )
+        .with(
+            SERVICE_SAF_TEHNIKA,
+            CHAR_TOTAL_READINGS,
+            MockCharacteristic::with_value(&3u16.to_le_bytes()),
+        )
+        .with(
+            SERVICE_SAF_TEHNIKA,
+            CHAR_SECONDS_SINCE_UPDATE,
+            MockCharacteristic::with_value(&10u16.to_le_bytes()),
+        )
+}
+
+/// Adds cmd and history characteristics which answer history requests for 3 samples
+fn with_history(dev: MockDevice) -> (MockDevice, MockCharacteristic) {
+    let cmd = MockCharacteristic::new();
+    let history = MockCharacteristic::new();
+
+    let queue = history.clone();
+    cmd.on_write(move |bytes| {
+        let (param, samples) = match bytes[1] {
+            1 => (HistoryParam::Temp, [400, 410, 420]),
+            2 => (HistoryParam::Humidity, [40, 41, 42]),
+            3 => (HistoryParam::Preasure, [10100, 10110, 10120]),
+            4 => (HistoryParam::C02, [600, 700, 800]),
+            _ => return,
+        };
+        let start = u16::from_le_bytes([bytes[2], bytes[3]]);
+        // Packets of at most two samples to exercise reassembly
+        for (i, chunk) in samples[start as usize - 1..].chunks(2).enumerate() {
+            queue.push_read(&history_packet(param, 3, start + 2 * i as u16, chunk));
+        }
+    });
+
+    let dev = dev.with(SERVICE_SAF_TEHNIKA, CHAR_CMD, cmd.clone()).with(
+        SERVICE_SAF_TEHNIKA,
+        CHAR_HISTORY_READINGS_V2,
+        history,
+    );
+    (dev, cmd)
+}
+
+#[tokio::test]
+async fn current_readings() {
+    let endpoint = map_device_endpoints(&aranet4()).await.unwrap();
+    let Reading::Aranet4(readings) = endpoint.read().await.unwrap() else {
+        panic!("Expected Aranet4 readings");
+    };
+
+    assert_eq!(readings.c02, 800);
+    assert_eq!(readings.temp.c_float(), 22.5);
+    assert_eq!(readings.preasure, 10132);
+    assert_eq!(readings.humidity, 45);
+    assert_eq!(readings.bat, 87);
+    assert_eq!(readings.status, 1);
+    assert_eq!(readings.measured, None);
+}
+
+#[tokio::test]
+async fn detailed_readings_carry_measurement_time() {
+    let dev = aranet4().with(
+        SERVICE_SAF_TEHNIKA,
+        CHAR_CURRENT_READINGS_DET,
+        // 900ppm, rest as aranet4(), 300s interval, 120s ago
+        MockCharacteristic::with_value(&[
+            0x84, 0x03, 0xc2, 0x01, 0x94, 0x27, 45, 87, 1, 0x2c, 0x01, 0x78, 0,
+        ]),
+    );
+    let endpoint = map_device_endpoints(&dev).await.unwrap();
+
+    let before = Utc::now();
+    let reading = endpoint.read().await.unwrap();
+    let after = Utc::now();
+    let Reading::Aranet4(readings) = &reading else {
+        panic!("Expected Aranet4 readings");
+    };
+    assert_eq!(readings.c02, 900);
+
+    let measured = reading.measured().unwrap();
+    assert_eq!(measured.interval, 300);
+    let ago = TimeDelta::seconds(120);
+    assert!(before - ago <= measured.time && measured.time <= after - ago);
+}
+
+#[tokio::test]
+async fn settings() {
+    let endpoint = map_device_endpoints(&aranet4()).await.unwrap();
+
+    assert_eq!(endpoint.read_interval().await.unwrap(), 60);
+    assert_eq!(endpoint.read_total_readings().await.unwrap(), 3);
+    assert_eq!(endpoint.read_seconds_since_update().await.unwrap(), 10);
+}
+
+#[tokio::test]
+async fn history() {
+    let (dev, cmd) = with_history(aranet4());
+    let endpoint = map_device_endpoints(&dev).await.unwrap();
+
+    let before = Utc::now();
+    let records = endpoint.read_history().await.unwrap();
+
+    assert_eq!(records.len(), 3);
+    assert_eq!(
+        records.iter().map(|x| x.c02).collect::<Vec<_>>(),
+        [600, 700, 800]
+    );
+    assert_eq!(records[2].temp.c_float(), 21.0);
+    assert_eq!(records[1].humidity, 41);
+    assert_eq!(records[0].preasure, 10100);
+
+    // Newest sample was taken 10s ago, the rest are spaced by the interval
+    assert!(records[2].time >= before - TimeDelta::seconds(10));
+    assert!(records[2].time <= Utc::now() - TimeDelta::seconds(10));
+    assert_eq!((records[2].time - records[1].time).num_seconds(), 60);
+    assert_eq!((records[1].time - records[0].time).num_seconds(), 60);
+
+    assert_eq!(
+        cmd.writes(),
+        [
+            [0x61, 1, 1, 0],
+            [0x61, 2, 1, 0],
+            [0x61, 3, 1, 0],
+            [0x61, 4, 1, 0]
+        ]
+    );
+}
+
+#[tokio::test]
+async fn incremental_history() {
+    let (dev, cmd) = with_history(aranet4());
+    let endpoint = map_device_endpoints(&dev).await.unwrap();
+
+    let (records, state) = endpoint.read_history_since(None).await.unwrap();
+    assert_eq!(records.len(), 3);
+    assert_eq!(state.interval, 60);
+    assert_eq!(state.newest, records[2].time);
+
+    // Last sync ended a minute before the newest record, so only that one is new
+    let last = SyncState {
+        interval: 60,
+        newest: state.newest - TimeDelta::seconds(60),
+    };
+    let (records, _) = endpoint.read_history_since(Some(&last)).await.unwrap();
+    assert_eq!(records.iter().map(|x| x.c02).collect::<Vec<_>>(), [800]);
+    assert_eq!(cmd.writes().last().unwrap(), &[0x61, 4, 3, 0]);
+
+    // Nothing new, the device isn't asked for history
+    let writes = cmd.writes().len();
+    let (records, unchanged) = endpoint.read_history_since(Some(&state)).await.unwrap();
+    assert!(records.is_empty());
+    assert_eq!(unchanged, state);
+    assert_eq!(cmd.writes().len(), writes);
+}
+
+#[tokio::test]
+async fn missed_history() {
+    let (dev, cmd) = with_history(aranet4());
+    let endpoint = map_device_endpoints(&dev).await.unwrap();
+    let newest = endpoint.read_log_state().await.unwrap().newest;
+
+    // Last seen one measurement ago, nothing missed
+    let records = endpoint
+        .read_missed(newest - TimeDelta::seconds(60))
+        .await
+        .unwrap();
+    assert!(records.is_empty());
+    assert!(cmd.writes().is_empty());
+
+    // Two measurements missed
+    let records = endpoint
+        .read_missed(newest - TimeDelta::seconds(120))
+        .await
+        .unwrap();
+    assert_eq!(
+        records.iter().map(|x| x.c02).collect::<Vec<_>>(),
+        [700, 800]
+    );
+}
+
+#[test]
+fn first_new_record() {
+    let now = Utc::now();
+    let log = LogState {
+        total: 2016,
+        interval: 300,
+        newest: now,
+    };
+    let synced = |minutes_ago, interval| SyncState {
+        interval,
+        newest: now - TimeDelta::minutes(minutes_ago),
+    };
+
+    assert_eq!(log.first_new(None), 1);
+    assert_eq!(log.first_new(Some(&synced(0, 300))), 2017);
+    // Full log, every new record pushed an old one out, so this is the tail either way
+    assert_eq!(log.first_new(Some(&synced(15, 300))), 2014);
+    // Seconds of jitter in the computed times don't matter
+    let jittered = SyncState {
+        newest: synced(15, 300).newest + TimeDelta::seconds(2),
+        ..synced(15, 300)
+    };
+    assert_eq!(log.first_new(Some(&jittered)), 2014);
+    // Gone longer than the log reaches back
+    assert_eq!(log.first_new(Some(&synced(60 * 24 * 30, 300))), 1);
+    // Changing the interval restarts the log
+    assert_eq!(log.first_new(Some(&synced(15, 60))), 1);
+    assert_eq!(log.time_of(2014), now - TimeDelta::minutes(10));
+}
+
+#[tokio::test]
+async fn history_skips_stale_packets() {
+    let history = MockCharacteristic::new();
+    let dev = aranet4()
+        .with(SERVICE_SAF_TEHNIKA, CHAR_CMD, MockCharacteristic::new())
+        .with(
+            SERVICE_SAF_TEHNIKA,
+            CHAR_HISTORY_READINGS_V2,
+            history.clone(),
+        );
+    let endpoint = map_device_endpoints(&dev).await.unwrap();
+
+    history.push_read(&history_packet(HistoryParam::Temp, 3, 1, &[400, 410, 420]));
+    history.push_read(&history_packet(HistoryParam::C02, 3, 1, &[600, 700, 800]));
+
+    let samples = endpoint
+        .read_history_param(HistoryParam::C02, 1, 3)
+        .await
+        .unwrap();
+    assert_eq!(samples, [600, 700, 800]);
+}
+
+#[tokio::test]
+async fn history_spanning_many_packets() {
+    let cmd = MockCharacteristic::new();
+    let history = MockCharacteristic::new();
+    let dev = aranet4()
+        .with(SERVICE_SAF_TEHNIKA, CHAR_CMD, cmd.clone())
+        .with(
+            SERVICE_SAF_TEHNIKA,
+            CHAR_HISTORY_READINGS_V2,
+            history.clone(),
+        );
+    let log: Vec<u16> = (0..750).map(|i| 400 + i).collect();
+    let queue = history.clone();
+    let served = log.clone();
+    cmd.on_write(move |bytes| {
+        let start = u16::from_le_bytes([bytes[2], bytes[3]]);
+        for (i, chunk) in served[start as usize - 1..].chunks(100).enumerate() {
+            let first = start + 100 * i as u16;
+            queue.push_read(&history_packet(HistoryParam::C02, 750, first, chunk));
+        }
+    });
+    let endpoint = map_device_endpoints(&dev).await.unwrap();
+
+    let samples = endpoint
+        .read_history_param(HistoryParam::C02, 1, 750)
+        .await
+        .unwrap();
+    assert_eq!(samples, log);
+
+    let samples = endpoint
+        .read_history_param(HistoryParam::C02, 201, 550)
+        .await
+        .unwrap();
+    assert_eq!(samples, log[200..]);
+}
+
+#[tokio::test]
+async fn history_gives_up_on_stale_packets() {
+    let dev = aranet4()
+        .with(SERVICE_SAF_TEHNIKA, CHAR_CMD, MockCharacteristic::new())
+        .with(
+            SERVICE_SAF_TEHNIKA,
+            CHAR_HISTORY_READINGS_V2,
+            MockCharacteristic::with_value(&history_packet(HistoryParam::Temp, 3, 1, &[400])),
+        );
+    let endpoint = map_device_endpoints(&dev).await.unwrap();
+
+    assert!(matches!(
+        endpoint.read_history_param(HistoryParam::C02, 1, 3).await,
+        Err(Error::Timeout(_))
+    ));
+}
+
+#[tokio::test]
+async fn missing_characteristic() {
+    let endpoint = map_device_endpoints(&MockDevice::new()).await.unwrap();
+
+    assert!(matches!(
+        endpoint.read().await,
+        Err(Error::MissingCharacteristic("current_readings"))
+    ));
+    assert!(matches!(
+        endpoint.read_interval().await,
+        Err(Error::MissingCharacteristic("interval"))
+    ));
+    assert!(endpoint.read_history().await.is_err());
+}
+
+#[tokio::test]
+async fn short_payload() {
+    let dev = MockDevice::new()
+        .with(
+            SERVICE_SAF_TEHNIKA,
+            CHAR_CURRENT_READINGS,
+            MockCharacteristic::with_value(&[0x20, 0x03, 0xc2]),
+        )
+        .with(
+            SERVICE_SAF_TEHNIKA,
+            CHAR_INTERVAL,
+            MockCharacteristic::with_value(&[60]),
+        );
+    let endpoint = map_device_endpoints(&dev).await.unwrap();
+
+    assert!(matches!(
+        endpoint.read().await,
+        Err(Error::ShortPayload {
+            name: "current_readings",
+            len: 3,
+            expected: 9
+        })
+    ));
+    assert!(matches!(
+        endpoint.read_interval().await,
+        Err(Error::ShortPayload { .. })
+    ));
+    assert!(HistoryPacket::parse(&[1, 60, 0], HistoryParam::Temp).is_err());
+    // Header claims more samples than the packet holds
+    assert!(
+        HistoryPacket::parse(&[1, 60, 0, 3, 0, 10, 0, 1, 0, 3, 0x90], HistoryParam::Temp).is_err()
+    );
+}
+
+#[tokio::test]
+async fn read_and_write_failures() {
+    let current = MockCharacteristic::new();
+    current.push_read_error("Not connected");
+    let (dev, cmd) = with_history(
+        MockDevice::new()
+            .with(SERVICE_SAF_TEHNIKA, CHAR_CURRENT_READINGS, current)
+            .with(
+                SERVICE_SAF_TEHNIKA,
+                CHAR_TOTAL_READINGS,
+                MockCharacteristic::with_value(&3u16.to_le_bytes()),
+            )
+            .with(
+                SERVICE_SAF_TEHNIKA,
+                CHAR_INTERVAL,
+                MockCharacteristic::with_value(&60u16.to_le_bytes()),
+            )
+            .with(
+                SERVICE_SAF_TEHNIKA,
+                CHAR_SECONDS_SINCE_UPDATE,
+                MockCharacteristic::with_value(&10u16.to_le_bytes()),
+            ),
+    );
+    cmd.fail_writes("Write not permitted");
+    let endpoint = map_device_endpoints(&dev).await.unwrap();
+
+    assert!(endpoint.read().await.is_err());
+    assert!(endpoint.read_history().await.is_err());
+}
+
+#[tokio::test]
+async fn aranet2_readings() {
+    // 21.0C, 45.3%, 90% battery
+    let plain = MockDevice::new().with(
+        SERVICE_SAF_TEHNIKA,
+        CHAR_CURRENT_READINGS_AR2,
+        MockCharacteristic::with_value(&[90, 0xa4, 0x01, 0xc5, 0x01, 0]),
+    );
+    let detailed = plain.clone().with(
+        SERVICE_SAF_TEHNIKA,
+        CHAR_CURRENT_READINGS_A_AR2,
+        MockCharacteristic::with_value(&[1, 0, 0x2c, 0x01, 0x78, 0, 90, 0xa4, 0x01, 0xc5, 0x01, 0]),
+    );
+
+    for dev in [plain, detailed] {
+        let endpoint = map_device_endpoints(&dev).await.unwrap();
+        assert_eq!(endpoint.model().await.unwrap(), DeviceModel::Aranet2);
+
+        let Reading::Aranet2(readings) = endpoint.read().await.unwrap() else {
+            panic!("Expected Aranet2 readings");
+        };
+        assert_eq!(readings.temp.c_float(), 21.0);
+        assert_eq!(readings.humidity_float(), 45.3);
+        assert_eq!(readings.bat, 90);
+    }
+}
+
+#[tokio::test]
+async fn radiation_and_radon_readings() {
+    let radiation = MockDevice::new().with(
+        SERVICE_SAF_TEHNIKA,
+        CHAR_CURRENT_READINGS_A_AR2,
+        MockCharacteristic::with_value(&[
+            2, 0, 0x2c, 0x01, 0x78, 0,  // Radiation, 300s interval, 120s ago
+            80, // battery
+            0x78, 0, 0, 0, // 120nSv/h
+            0x40, 0x42, 0x0f, 0, // 1mSv
+            0x80, 0x51, 0x01, 0, 0, 0, 0, 0, // 24h
+            0,
+        ]),
+    );
+    let endpoint = map_device_endpoints(&radiation).await.unwrap();
+    assert_eq!(
+        endpoint.model().await.unwrap(),
+        DeviceModel::AranetRadiation
+    );
+    let Reading::Radiation(readings) = endpoint.read().await.unwrap() else {
+        panic!("Expected radiation readings");
+    };
+    assert_eq!(readings.dose_rate_float(), 0.12);
+    assert_eq!(readings.dose_float(), 1.0);
+    assert_eq!(readings.dose_duration, 86400);
+    assert_eq!(readings.bat, 80);
+
+    let radon = MockDevice::new().with(
+        SERVICE_SAF_TEHNIKA,
+        CHAR_CURRENT_READINGS_A_AR2,
+        MockCharacteristic::with_value(&[
+            3, 0, 0x58, 0x02, 0x3c, 0,  // Radon Plus, 600s interval, 60s ago
+            95, // battery
+            0xa4, 0x01, // 21.0C
+            0x94, 0x27, // 1013.2hPa
+            0xc5, 0x01, // 45.3%
+            0x2d, 0, 0, 0, // 45Bq/m³
+            0,
+        ]),
+    );
+    let endpoint = map_device_endpoints(&radon).await.unwrap();
+    assert_eq!(
+        endpoint.model().await.unwrap(),
+        DeviceModel::AranetRadonPlus
+    );
+    let Reading::Radon(readings) = endpoint.read().await.unwrap() else {
+        panic!("Expected radon readings");
+    };
+    assert_eq!(readings.radon, 45);
+    assert_eq!(readings.temp.c_float(), 21.0);
+    assert_eq!(readings.preasure, 10132);
+    assert_eq!(readings.humidity_float(), 45.3);
+    assert!(endpoint.read_history().await.is_err());
+}
+
+#[tokio::test]
+async fn device_info() {
+    let dev = aranet4()
+        .with(
+            SERVICE_DIS,
+            CHAR_MODEL_NUMBER,
+            MockCharacteristic::with_value(b"Aranet4\0"),
+        )
+        .with(
+            SERVICE_DIS,
+            CHAR_SERIAL_NO,
+            MockCharacteristic::with_value(b"12345"),
+        )
+        .with(
+            SERVICE_DIS,
+            CHAR_SW_REV,
+            MockCharacteristic::with_value(b"v1.4.19"),
+        )
+        .with(
+            SERVICE_BATTTERY,
+            CHAR_BATTERY_LEVEL,
+            MockCharacteristic::with_value(&[87]),
+        );
+    let endpoint = map_device_endpoints(&dev).await.unwrap();
+    let info = endpoint.read_info().await.unwrap();
+
+    assert_eq!(info.model_number.as_deref(), Some("Aranet4"));
+    assert_eq!(info.serial_number.as_deref(), Some("12345"));
+    assert_eq!(info.firmware_revision.as_deref(), Some("v1.4.19"));
+    assert_eq!(info.hardware_revision, None);
+    assert_eq!(info.battery_level, Some(87));
+}
+
+/// Adds cmd, interval and sensor_state characteristics which apply settings commands,
+/// unless `ignore` is set
+fn with_settings(dev: MockDevice, ignore: bool) -> (MockDevice, MockCharacteristic) {
+    let cmd = MockCharacteristic::new();
+    let interval = MockCharacteristic::with_value(&300u16.to_le_bytes());
+    let state = MockCharacteristic::with_value(&[0, 0]);
+
+    let (i, st) = (interval.clone(), state.clone());
+    cmd.on_write(move |bytes| {
+        if ignore {
+            return;
+        }
+        let toggle = |bit: u8, on: u8| {
+            let current = st.value().unwrap()[1];
+            let next = if on != 0 {
+                current | bit
+            } else {
+                current & !bit
+            };
+            st.set_value(&[0, next]);
+        };
+        match bytes {
+            [0x90, minutes] => i.set_value(&(*minutes as u16 * 60).to_le_bytes()),
+            [0x91, on] => toggle(1 << 5, *on),
+            [0x92, on] => toggle(1 << 7, *on),
+            [0x93, on] => toggle(1 << 4, *on),
+            _ => {}
+        }
+    });
+
+    let dev = dev
+        .with(SERVICE_SAF_TEHNIKA, CHAR_CMD, cmd.clone())
+        .with(SERVICE_SAF_TEHNIKA, CHAR_INTERVAL, interval)
+        .with(SERVICE_SAF_TEHNIKA, CHAR_SENSOR_STATE, state);
+    (dev, cmd)
+}
+
+#[tokio::test]
+async fn change_settings() {
+    let (dev, cmd) = with_settings(MockDevice::new(), false);
+    let endpoint = map_device_endpoints(&dev).await.unwrap();
+
+    let settings = endpoint.read_settings().await.unwrap();
+    assert_eq!(settings.interval, Interval::Min5);
+    assert!(!settings.smart_home);
+
+    endpoint
+        .apply(Command::SetInterval(Interval::Min1))
+        .await
+        .unwrap();
+    endpoint.apply(Command::SetSmartHome(true)).await.unwrap();
+    endpoint
+        .apply(Command::SetRange(BluetoothRange::Extended))
+        .await
+        .unwrap();
+    let settings = endpoint
+        .apply(Command::SetTempUnit(TempUnit::Fahrenheit))
+        .await
+        .unwrap();
+
+    assert_eq!(
+        settings,
+        Settings {
+            interval: Interval::Min1,
+            smart_home: true,
+            range: BluetoothRange::Extended,
+            temp_unit: TempUnit::Fahrenheit,
+        }
+    );
+    assert_eq!(
+        cmd.writes(),
+        [vec![0x90, 1], vec![0x91, 1], vec![0x92, 1], vec![0x93, 1]]
+    );
+}
+
+#[tokio::test]
+async fn settings_not_applied() {
+    let (dev, _) = with_settings(MockDevice::new(), true);
+    let endpoint = map_device_endpoints(&dev).await.unwrap();
+
+    assert!(matches!(
+        endpoint.apply(Command::SetInterval(Interval::Min10)).await,
+        Err(Error::NotApplied(_))
+    ));
+    assert!(endpoint.apply(Command::SetSmartHome(true)).await.is_err());
+}
+
+#[tokio::test]
+async fn calibration() {
+    let (dev, cmd) = with_settings(MockDevice::new(), false);
+    let data = MockCharacteristic::with_value(&[0, 0]);
+    let dev = dev.with(SERVICE_SAF_TEHNIKA, CHAR_CALIBRATION_DATA, data.clone());
+    let endpoint = map_device_endpoints(&dev).await.unwrap();
+
+    let calibration = endpoint.read_calibration().await.unwrap();
+    assert_eq!(calibration.state, CalibrationState::NotActive);
+    assert!(!calibration.auto);
+
+    // Device never picked the command up
+    assert!(endpoint.calibrate().await.is_err());
+
+    data.set_value(&[2, 15]);
+    let calibration = endpoint.calibrate().await.unwrap();
+    assert_eq!(calibration.state, CalibrationState::InProgress);
+    assert_eq!(calibration.progress, 15);
+    assert_eq!(cmd.writes().last().unwrap(), &[0x94, 0]);
+
+    // Auto calibration isn't scripted by with_settings
+    assert!(endpoint.set_auto_calibration(true).await.is_err());
+}
@@ -0,0 +1,140 @@
+mod common;
+
+use std::{collections::BTreeMap, time::Duration};
+
+use aranet::{
+    influx::{Api, Influx},
+    metric::DeviceLabels,
+    sink::{BatchWriter, Batched, Sink},
+    types::Sample,
+};
+use chrono::DateTime;
+use common::{at, sample, stub};
+
+const DEVICE: &str = "AA:AA:AA:AA:AA:AA";
+
+fn v2() -> Api {
+    Api::V2 {
+        org: "home".to_string(),
+        bucket: "air".to_string(),
+        token: "secret".to_string(),
+    }
+}
+
+#[test]
+fn line_protocol() {
+    let mut influx = Influx::new("http://localhost:8086", v2());
+    influx.set_tags(BTreeMap::from([("site".to_string(), "a b".to_string())]));
+    influx.set_labels(&DeviceLabels {
+        address: "AA:AA:AA:AA:AA:AA".to_string(),
+        name: "office,north".to_string(),
+        room: String::new(),
+    });
+    assert_eq!(
+        influx.line(&sample(DEVICE, at(1_700_000_000), 650)).unwrap(),
+        "aranet,device=AA:AA:AA:AA:AA:AA,model=Aranet4,name=office\\,north,site=a\\ b \
+         co2_ppm=650i,battery_percent=90i,temperature_c=21.5,humidity_percent=40,pressure_hpa=1013.2 1700000000"
+    );
+    let mut radiation = Sample::empty("CC:CC:CC:CC:CC:CC", DateTime::from_timestamp(0, 0).unwrap());
+    radiation.dose_rate = Some(0.12);
+    radiation.dose = Some(0.005);
+    assert_eq!(
+        influx.line(&radiation).unwrap(),
+        "aranet,device=CC:CC:CC:CC:CC:CC,site=a\\ b dose_rate_nsv_h=120i,dose_nsv=5000i 0"
+    );
+    let empty = Sample::empty("AA:AA:AA:AA:AA:AA", DateTime::from_timestamp(0, 0).unwrap());
+    assert_eq!(influx.line(&empty), None);
+}
+
+#[test]
+fn posts_with_measurement_time() {
+    let (url, requests) = stub(vec![204]);
+    let mut influx = Influx::new(&url, v2());
+    influx.set_measurement("air quality");
+
+    influx
+        .queue(&[
+            sample(DEVICE, at(1_700_000_000), 650),
+            sample(DEVICE, at(1_700_000_060), 700),
+        ])
+        .unwrap();
+    assert_eq!(influx.flush().unwrap(), 2);
+    let (request, body) = requests.recv().unwrap();
+    assert_eq!(
+        request,
+        "POST /api/v2/write?org=home&bucket=air&precision=s HTTP/1.1 Authorization: Token secret"
+    );
+    let body = String::from_utf8(body).unwrap();
+    let lines: Vec<&str> = body.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].starts_with("air\\ quality,device=AA:AA:AA:AA:AA:AA"));
+    assert!(lines[1].ends_with(" 1700000060"));
+    assert_eq!(influx.pending(), 0);
+}
+
+#[test]
+fn buffers_to_disk_while_unreachable() {
+    let buffer = std::env::temp_dir().join(format!("aranet-influx-{}.buffer", std::process::id()));
+    let (url, requests) = stub(vec![503, 204]);
+    let api = Api::V1 {
+        database: "air".to_string(),
+        username: None,
+        password: None,
+    };
+
+    let mut influx = Influx::new(&url, api.clone());
+    influx.set_buffer(&buffer).unwrap();
+    influx
+        .queue(&[sample(DEVICE, at(1_700_000_000), 650)])
+        .unwrap();
+    assert!(influx.flush().is_err());
+    assert!(influx.retry_at().is_some());
+    assert_eq!(
+        requests.recv().unwrap().0.split(' ').nth(1),
+        Some("/write?db=air&precision=s")
+    );
+    influx
+        .queue(&[sample(DEVICE, at(1_700_000_060), 700)])
+        .unwrap();
+    assert_eq!(influx.pending(), 2);
+    drop(influx);
+
+    // A restart picks the spool up again
+    let mut influx = Influx::new(&url, api);
+    influx.set_buffer(&buffer).unwrap();
+    assert_eq!(influx.pending(), 2);
+    assert_eq!(influx.flush().unwrap(), 2);
+    assert_eq!(
+        String::from_utf8(requests.recv().unwrap().1)
+            .unwrap()
+            .lines()
+            .count(),
+        2
+    );
+    assert_eq!(std::fs::read_to_string(&buffer).unwrap(), "");
+
+    std::fs::remove_file(&buffer).unwrap();
+}
+
+#[test]
+fn flushes_full_batches_in_the_background() {
+    let (url, requests) = stub(vec![204]);
+    let mut influx = Influx::new(&url, v2());
+    influx.set_batch_size(2);
+    // Far off, only a full batch gets sent
+    let mut sink = Batched::start("influx", influx, Duration::from_secs(3600));
+
+    assert_eq!(
+        sink.write(&[sample(DEVICE, at(1_700_000_000), 650)])
+            .unwrap(),
+        1
+    );
+    assert!(requests.recv_timeout(Duration::from_millis(200)).is_err());
+    assert_eq!(
+        sink.write(&[sample(DEVICE, at(1_700_000_060), 700)])
+            .unwrap(),
+        1
+    );
+    let (_, body) = requests.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(String::from_utf8(body).unwrap().lines().count(), 2);
+}
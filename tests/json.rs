@@ -0,0 +1,98 @@
+use aranet::types::{
+    Aranet2Reading, CurrentReading, HistoryRecord, Measured, RadiationReading, Reading, Temp,
+};
+use chrono::{DateTime, TimeDelta};
+use serde_json::json;
+
+#[test]
+fn readings_use_documented_fields() {
+    let time = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+    let reading = Reading::Aranet4(CurrentReading {
+        c02: 800,
+        temp: Temp::new(450),
+        preasure: 10132,
+        humidity: 45,
+        bat: 87,
+        status: 1,
+        measured: Some(Measured {
+            time,
+            interval: 300,
+        }),
+    });
+    assert_eq!(
+        serde_json::to_value(&reading).unwrap(),
+        json!({
+            "model": "Aranet4",
+            "co2_ppm": 800,
+            "temperature_c": 22.5,
+            "pressure_hpa": 1013.2,
+            "humidity_percent": 45,
+            "battery_percent": 87,
+            "status": 1,
+            "measured": {"time": "2023-11-14T22:13:20Z", "interval_s": 300},
+        })
+    );
+    assert_eq!(
+        serde_json::to_value(reading.timed(time + TimeDelta::seconds(90))).unwrap()["time"],
+        "2023-11-14T22:13:20Z"
+    );
+
+    let reading = Reading::Aranet2(Aranet2Reading {
+        temp: Temp::new(450),
+        humidity: 455,
+        bat: 50,
+        status: 0,
+        measured: None,
+    });
+    assert_eq!(
+        serde_json::to_value(&reading).unwrap(),
+        json!({
+            "model": "Aranet2",
+            "temperature_c": 22.5,
+            "humidity_percent": 45.5,
+            "battery_percent": 50,
+            "status": 0,
+            "measured": null,
+        })
+    );
+
+    // Without a measurement time the read time stands in
+    let read = DateTime::from_timestamp(1_700_000_060, 0).unwrap();
+    let timed = serde_json::to_value(reading.timed(read)).unwrap();
+    assert_eq!(timed["time"], "2023-11-14T22:14:20Z");
+    assert_eq!(timed["model"], "Aranet2");
+
+    let reading = Reading::Radiation(RadiationReading {
+        dose_rate: 120,
+        dose: 5000,
+        dose_duration: 3600,
+        bat: 90,
+        status: 0,
+        measured: None,
+    });
+    assert_eq!(
+        serde_json::to_value(&reading).unwrap()["model"],
+        "Aranet Radiation"
+    );
+}
+
+#[test]
+fn history_records_use_documented_fields() {
+    let record = HistoryRecord {
+        time: DateTime::from_timestamp(1_700_000_000, 0).unwrap(),
+        c02: 650,
+        temp: Temp::new(430),
+        preasure: 9987,
+        humidity: 40,
+    };
+    assert_eq!(
+        serde_json::to_value(&record).unwrap(),
+        json!({
+            "time": "2023-11-14T22:13:20Z",
+            "co2_ppm": 650,
+            "temperature_c": 21.5,
+            "pressure_hpa": 998.7,
+            "humidity_percent": 40,
+        })
+    );
+}
@@ -0,0 +1,110 @@
+mod common;
+
+use aranet::{
+    metric::DeviceLabels,
+    mqtt::{state_payload, Publisher, Topics},
+    types::{DeviceModel, Sample},
+};
+use chrono::DateTime;
+use common::{at, sample};
+use serde_json::Value;
+
+fn publisher() -> Publisher {
+    let mut topics = Topics::new(
+        "home/{room}/{device}",
+        "aranet/status",
+        Some("homeassistant"),
+    );
+    topics.set_labels(&DeviceLabels {
+        address: "AA:AA:AA:AA:AA:AA".to_string(),
+        name: "office-north".to_string(),
+        room: "4.12".to_string(),
+    });
+    Publisher::new(topics)
+}
+
+#[test]
+fn announces_then_publishes_latest() {
+    let mut publisher = publisher();
+    let messages = publisher.messages(&[
+        sample("AA:AA:AA:AA:AA:AA", at(1_700_000_000), 600),
+        sample("AA:AA:AA:AA:AA:AA", at(1_700_000_060), 650),
+    ]);
+    let topics: Vec<&str> = messages.iter().map(|x| x.topic.as_str()).collect();
+    assert_eq!(
+        topics,
+        [
+            "homeassistant/sensor/aranet_aaaaaaaaaaaa/co2_ppm/config",
+            "homeassistant/sensor/aranet_aaaaaaaaaaaa/temperature_c/config",
+            "homeassistant/sensor/aranet_aaaaaaaaaaaa/humidity_percent/config",
+            "homeassistant/sensor/aranet_aaaaaaaaaaaa/pressure_hpa/config",
+            "homeassistant/sensor/aranet_aaaaaaaaaaaa/battery_percent/config",
+            "home/4.12/office-north/availability",
+            "home/4.12/office-north",
+        ]
+    );
+    assert!(messages.iter().all(|x| x.retain));
+
+    let config: Value = serde_json::from_str(&messages[0].payload).unwrap();
+    assert_eq!(config["state_topic"], "home/4.12/office-north");
+    assert_eq!(config["value_template"], "{{ value_json.co2_ppm }}");
+    assert_eq!(config["device_class"], "carbon_dioxide");
+    assert_eq!(config["device"]["name"], "office-north");
+    assert_eq!(config["device"]["suggested_area"], "4.12");
+    assert_eq!(config["availability"][0]["topic"], "aranet/status");
+
+    let state: Value = serde_json::from_str(&messages[6].payload).unwrap();
+    assert_eq!(state["co2_ppm"], 650);
+    assert_eq!(state["time"], "2023-11-14T22:14:20Z");
+    assert!(messages[6].state);
+
+    // Announced once, older samples from a backfill don't replace the current values
+    let messages = publisher.messages(&[
+        sample("AA:AA:AA:AA:AA:AA", at(1_700_000_030), 620),
+        sample("AA:AA:AA:AA:AA:AA", at(1_700_000_120), 700),
+    ]);
+    assert_eq!(messages.len(), 1);
+    assert!(messages[0].payload.contains("\"co2_ppm\":700"));
+    assert!(publisher
+        .messages(&[sample("AA:AA:AA:AA:AA:AA", at(1_700_000_090), 680)])
+        .is_empty());
+}
+
+#[test]
+fn availability_changes() {
+    let mut publisher = publisher();
+    publisher.messages(&[sample("BB:BB:BB:BB:BB:BB", at(1_700_000_000), 600)]);
+    assert_eq!(publisher.set_online("BB:BB:BB:BB:BB:BB", true), None);
+
+    let offline = publisher.set_online("BB:BB:BB:BB:BB:BB", false).unwrap();
+    assert_eq!(offline.topic, "home//BBBBBBBBBBBB/availability");
+    assert_eq!(offline.payload, "offline");
+    assert_eq!(publisher.set_online("BB:BB:BB:BB:BB:BB", false), None);
+}
+
+#[test]
+fn radiation_in_nsv() {
+    let mut sample = Sample::empty(
+        "CC:CC:CC:CC:CC:CC",
+        DateTime::from_timestamp(1_700_000_000, 0).unwrap(),
+    );
+    sample.model = Some(DeviceModel::AranetRadiation);
+    sample.dose_rate = Some(0.12);
+    sample.dose = Some(0.005);
+
+    let state = state_payload(&sample);
+    assert_eq!(state["dose_rate_nsv_h"], 120);
+    assert_eq!(state["dose_nsv"], 5000);
+    assert_eq!(state["model"], "Aranet Radiation");
+
+    let messages = publisher().messages(&[sample]);
+    let class = |field: &str| {
+        let config = messages
+            .iter()
+            .find(|x| x.topic.ends_with(&format!("/{field}/config")))
+            .unwrap();
+        serde_json::from_str::<Value>(&config.payload).unwrap()["state_class"].clone()
+    };
+    assert_eq!(class("dose_rate_nsv_h"), "measurement");
+    assert_eq!(class("dose_nsv"), "total_increasing");
+}
@@ -0,0 +1,10 @@
+use aranet::bluetooth::parse_passkey;
+
+#[test]
+fn passkeys() {
+    assert_eq!(parse_passkey("123456").unwrap(), 123456);
+    assert_eq!(parse_passkey("012345\n").unwrap(), 12345);
+    assert!(parse_passkey("").is_err());
+    assert!(parse_passkey("12345").is_err());
+    assert!(parse_passkey("12a456").is_err());
+}
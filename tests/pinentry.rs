@@ -0,0 +1,49 @@
+use std::io::Cursor;
+
+use aranet::{
+    pinentry::{percent_decode, percent_encode, Client},
+    Error,
+};
+
+fn client(server: &str) -> Client<Cursor<Vec<u8>>, Vec<u8>> {
+    Client::connect(Cursor::new(server.as_bytes().to_vec()), Vec::new()).unwrap()
+}
+
+#[test]
+fn get_pin() {
+    // Greeting, replies to SETTITLE, SETDESC and SETPROMPT, then the PIN with a leading zero
+    let mut client = client(
+        "OK Pleased to meet you\nOK\nOK\n# comment\nOK\nS PASSWORD_FROM_CACHE\nD 012345\nOK\n",
+    );
+    assert_eq!(
+        client.get_pin("Bluetooth PIN", "100%\nsure").unwrap(),
+        Some("012345".to_string())
+    );
+}
+
+#[test]
+fn cancelled() {
+    let mut client = client("OK\nOK\nOK\nOK\nERR 83886179 Operation cancelled <Pinentry>\n");
+    assert_eq!(client.get_pin("Bluetooth PIN", "").unwrap(), None);
+}
+
+#[test]
+fn errors() {
+    let mut client = client("OK\nERR 83886355 Not confirmed%0A\n");
+    let Err(Error::Pinentry(e)) = client.command("CONFIRM") else {
+        panic!("Expected an ERR reply");
+    };
+    assert_eq!(e.message, "Not confirmed\n");
+    assert!(!e.is_cancelled());
+
+    // Server went away before answering
+    let mut client = self::client("OK\n");
+    assert!(client.command("GETPIN").is_err());
+}
+
+#[test]
+fn percent_escapes() {
+    assert_eq!(percent_encode("100%\r\n"), "100%25%0D%0A");
+    assert_eq!(percent_decode("100%25%0D%0A").unwrap(), "100%\r\n");
+    assert!(percent_decode("%2").is_err());
+}
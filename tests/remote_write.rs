@@ -0,0 +1,159 @@
+mod common;
+
+use std::{collections::BTreeMap, time::Duration};
+
+use aranet::{
+    metric::DeviceLabels,
+    remote_write::{Label, RemoteWrite, WriteRequest},
+    sink::{BatchWriter, Batched, Sink},
+    types::Sample,
+};
+use common::{at, sample, stub};
+use prost::Message;
+
+const DEVICE: &str = "AA:AA:AA:AA:AA:AA";
+
+fn label(name: &str, value: &str) -> Label {
+    Label {
+        name: name.to_string(),
+        value: value.to_string(),
+    }
+}
+
+#[test]
+fn series_per_gauge() {
+    let mut remote = RemoteWrite::new("http://localhost:9090/api/v1/write");
+    remote.set_external_labels(BTreeMap::from([("site".to_string(), "home".to_string())]));
+    remote.set_labels(&DeviceLabels {
+        address: DEVICE.to_string(),
+        name: "office-north".to_string(),
+        room: String::new(),
+    });
+    let request = remote.request(&[
+        sample(DEVICE, at(1_700_000_060), 700),
+        sample(DEVICE, at(1_700_000_000), 650),
+    ]);
+
+    let names: Vec<&str> = request
+        .timeseries
+        .iter()
+        .map(|x| x.labels[0].value.as_str())
+        .collect();
+    assert_eq!(
+        names,
+        [
+            "aranet_bat",
+            "aranet_co2",
+            "aranet_preasure",
+            "aranet_relative_humidity",
+            "aranet_temp_celsius",
+            "aranet_temp_fahrenheit",
+        ]
+    );
+    let co2 = &request.timeseries[1];
+    assert_eq!(
+        co2.labels,
+        [
+            label("__name__", "aranet_co2"),
+            label("address", DEVICE),
+            label("name", "office-north"),
+            label("site", "home"),
+        ]
+    );
+    let points: Vec<(i64, f64)> = co2.samples.iter().map(|x| (x.timestamp, x.value)).collect();
+    assert_eq!(
+        points,
+        [(1_700_000_000_000, 650.0), (1_700_000_060_000, 700.0)]
+    );
+
+    let mut radiation = Sample::empty("CC:CC:CC:CC:CC:CC", at(0));
+    radiation.dose_rate = Some(0.12);
+    let request = remote.request(&[radiation]);
+    assert_eq!(request.timeseries.len(), 1);
+    assert_eq!(
+        request.timeseries[0].labels,
+        [
+            label("__name__", "aranet_radiation_dose_rate"),
+            label("address", "CC:CC:CC:CC:CC:CC"),
+            label("site", "home"),
+        ]
+    );
+}
+
+#[test]
+fn posts_snappy_protobuf() {
+    let (url, requests) = stub(vec![204]);
+    let mut remote = RemoteWrite::new(&format!("{url}/api/v1/write"));
+    remote.set_bearer_token("secret");
+
+    remote
+        .queue(&[
+            sample(DEVICE, at(1_700_000_000), 650),
+            sample(DEVICE, at(1_700_000_060), 700),
+        ])
+        .unwrap();
+    assert_eq!(remote.flush().unwrap(), 2);
+    let (request, body) = requests.recv().unwrap();
+    assert_eq!(
+        request,
+        "POST /api/v1/write HTTP/1.1 Authorization: Bearer secret"
+    );
+    let body = snap::raw::Decoder::new().decompress_vec(&body).unwrap();
+    let sent = WriteRequest::decode(body.as_slice()).unwrap();
+    assert_eq!(sent.timeseries.len(), 6);
+    assert!(sent.timeseries.iter().all(|x| x.samples.len() == 2));
+    assert_eq!(remote.pending(), 0);
+}
+
+#[test]
+fn retries_unless_rejected() {
+    let (url, requests) = stub(vec![503, 400, 204]);
+    let mut remote = RemoteWrite::new(&url);
+    remote.set_basic_auth("aranet", "pass");
+    remote.set_batch_size(1);
+
+    remote
+        .queue(&[sample(DEVICE, at(1_700_000_000), 650)])
+        .unwrap();
+    assert!(remote.flush().is_err());
+    assert!(remote.retry_at().is_some());
+    assert_eq!(
+        requests.recv().unwrap().0,
+        "POST / HTTP/1.1 Authorization: Basic YXJhbmV0OnBhc3M="
+    );
+    assert_eq!(remote.pending(), 1);
+
+    // Out of order for the server, dropped rather than retried forever
+    remote
+        .queue(&[sample(DEVICE, at(1_700_000_060), 700)])
+        .unwrap();
+    assert_eq!(remote.flush().unwrap(), 1);
+    assert_eq!(requests.iter().take(2).count(), 2);
+    assert_eq!(remote.pending(), 0);
+    assert!(remote.retry_at().is_none());
+}
+
+#[test]
+fn sends_full_batches_in_the_background() {
+    let (url, requests) = stub(vec![204]);
+    let mut remote = RemoteWrite::new(&url);
+    remote.set_batch_size(2);
+    // Far off, only a full batch gets sent
+    let mut sink = Batched::start("remote_write", remote, Duration::from_secs(3600));
+
+    assert_eq!(
+        sink.write(&[sample(DEVICE, at(1_700_000_000), 650)])
+            .unwrap(),
+        1
+    );
+    assert!(requests.recv_timeout(Duration::from_millis(200)).is_err());
+    assert_eq!(
+        sink.write(&[sample(DEVICE, at(1_700_000_060), 700)])
+            .unwrap(),
+        1
+    );
+    let (_, body) = requests.recv_timeout(Duration::from_secs(5)).unwrap();
+    let body = snap::raw::Decoder::new().decompress_vec(&body).unwrap();
+    let sent = WriteRequest::decode(body.as_slice()).unwrap();
+    assert!(sent.timeseries.iter().all(|x| x.samples.len() == 2));
+}
@@ -0,0 +1,69 @@
+use std::time::Duration;
+
+use aranet::{
+    schedule::{next_poll, Backoff, PollStats, MARGIN},
+    types::Measured,
+};
+use chrono::{TimeDelta, Utc};
+
+#[test]
+fn polls_just_after_next_measurement() {
+    let now = Utc::now();
+    let measured = Measured {
+        time: now - TimeDelta::seconds(100),
+        interval: 300,
+    };
+    assert_eq!(
+        next_poll(measured, now),
+        Some(Duration::from_secs(200) + MARGIN)
+    );
+
+    // Measurements missed while away are skipped
+    let stale = Measured {
+        time: now - TimeDelta::seconds(700),
+        interval: 300,
+    };
+    assert_eq!(
+        next_poll(stale, now),
+        Some(Duration::from_secs(200) + MARGIN)
+    );
+
+    let unknown = Measured {
+        time: now,
+        interval: 0,
+    };
+    assert_eq!(next_poll(unknown, now), None);
+}
+
+#[test]
+fn counts_duplicates() {
+    let now = Utc::now();
+    let at = |secs| Measured {
+        time: now + TimeDelta::seconds(secs),
+        interval: 60,
+    };
+    let mut stats = PollStats::default();
+
+    assert!(stats.record(Some(at(0))));
+    // Same measurement with a second of jitter
+    assert!(!stats.record(Some(at(1))));
+    assert!(stats.record(Some(at(60))));
+    assert!(stats.record(None));
+
+    assert_eq!((stats.new, stats.duplicate), (3, 1));
+    assert_eq!(stats.to_string(), "3 new, 1 duplicate");
+}
+
+#[test]
+fn backoff_grows_with_jitter_up_to_max() {
+    let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(10));
+    for ceiling in [1, 2, 4, 8, 10, 10] {
+        let ceiling = Duration::from_secs(ceiling);
+        let delay = backoff.next_delay();
+        assert!(ceiling / 2 <= delay && delay <= ceiling, "{delay:?}");
+    }
+    assert_eq!(backoff.attempts(), 6);
+
+    backoff.reset();
+    assert_eq!(backoff.ceiling(), Duration::from_secs(1));
+}
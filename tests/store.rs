@@ -0,0 +1,86 @@
+mod common;
+
+use aranet::{
+    sink::{self, Sink},
+    store::Store,
+    types::SyncState,
+};
+use chrono::{DateTime, TimeDelta};
+use common::sample;
+
+#[test]
+fn insert_and_query() {
+    let mut store = Store::open_in_memory().unwrap();
+    let t0 = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+    let minutes = |x| t0 + TimeDelta::minutes(x);
+
+    let samples = [
+        sample("AA:AA:AA:AA:AA:AA", minutes(0), 600),
+        sample("AA:AA:AA:AA:AA:AA", minutes(1), 700),
+        sample("BB:BB:BB:BB:BB:BB", minutes(1), 800),
+    ];
+    assert_eq!(store.insert_all(&samples).unwrap(), 3);
+
+    // Same measurement read again, a second later
+    let again = sample("AA:AA:AA:AA:AA:AA", minutes(1) + TimeDelta::seconds(1), 700);
+    assert!(!store.insert(&again).unwrap());
+    assert!(store
+        .insert(&sample("AA:AA:AA:AA:AA:AA", minutes(2), 900))
+        .unwrap());
+
+    let all = store.query(None, None, None).unwrap();
+    assert_eq!(all.len(), 4);
+    assert_eq!(all[0], samples[0]);
+
+    let range = store
+        .query(
+            Some("AA:AA:AA:AA:AA:AA"),
+            Some(minutes(1)),
+            Some(minutes(2)),
+        )
+        .unwrap();
+    let c02: Vec<_> = range.iter().map(|x| x.c02.unwrap()).collect();
+    assert_eq!(c02, [700, 900]);
+
+    assert_eq!(store.latest("AA:AA:AA:AA:AA:AA").unwrap(), Some(minutes(2)));
+    assert_eq!(store.latest("CC:CC:CC:CC:CC:CC").unwrap(), None);
+}
+
+#[test]
+fn sync_state() {
+    let store = Store::open_in_memory().unwrap();
+    assert_eq!(store.sync_state("AA:AA:AA:AA:AA:AA").unwrap(), None);
+
+    let state = SyncState {
+        interval: 300,
+        newest: DateTime::from_timestamp(1_700_000_000, 0).unwrap(),
+    };
+    store.set_sync_state("AA:AA:AA:AA:AA:AA", &state).unwrap();
+    let newer = SyncState {
+        newest: state.newest + TimeDelta::minutes(5),
+        ..state
+    };
+    store.set_sync_state("AA:AA:AA:AA:AA:AA", &newer).unwrap();
+    assert_eq!(store.sync_state("AA:AA:AA:AA:AA:AA").unwrap(), Some(newer));
+}
+
+#[test]
+fn store_as_sink() {
+    let t0 = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+    let mut sinks: Vec<Box<dyn Sink>> = vec![Box::new(Store::open_in_memory().unwrap())];
+    assert_eq!(sink::latest(&sinks, "AA:AA:AA:AA:AA:AA"), None);
+
+    let samples = [
+        sample("AA:AA:AA:AA:AA:AA", t0, 600),
+        sample("AA:AA:AA:AA:AA:AA", t0 + TimeDelta::minutes(1), 700),
+    ];
+    let written: Vec<_> = sink::write_all(&mut sinks, &samples)
+        .into_iter()
+        .map(Result::unwrap)
+        .collect();
+    assert_eq!(written, [2]);
+    assert_eq!(
+        sink::latest(&sinks, "AA:AA:AA:AA:AA:AA"),
+        Some(t0 + TimeDelta::minutes(1))
+    );
+}